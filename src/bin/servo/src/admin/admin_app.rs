@@ -0,0 +1,247 @@
+use std::{collections::HashSet, net::IpAddr, sync::Arc};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use http::{Method, Response, StatusCode};
+use log::{debug, info};
+use matchit::Router;
+use pingora::{apps::http_app::ServeHttp, protocols::http::ServerSession};
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::{
+    admin::{Error, MaintenanceView, ServerSummaryView, ServerView},
+    config_toml::{AdminToml, LocationToml, ServerToml},
+    server_map::{ServerMap, server_map::Error as ServerMapError},
+};
+
+// a whole server config is a few kb, anything past this is refused
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+enum AdminRoute {
+    Config,
+    Servers,
+    Server,
+    Maintenance,
+    Locations,
+    Location,
+}
+
+// a json api over the live server map, mutations go through the same
+// validation as the config file and are not written back to it
+pub struct AdminApp {
+    server_map: Arc<ServerMap>,
+    allowed_ips: HashSet<IpAddr>,
+    router: Router<AdminRoute>,
+}
+
+impl AdminApp {
+    pub fn new(server_map: Arc<ServerMap>, admin_toml: &AdminToml) -> Self {
+        let mut router = Router::new();
+        let routes = [
+            ("/config", AdminRoute::Config),
+            ("/servers", AdminRoute::Servers),
+            ("/servers/{server}", AdminRoute::Server),
+            ("/servers/{server}/maintenance", AdminRoute::Maintenance),
+            ("/servers/{server}/locations", AdminRoute::Locations),
+            ("/servers/{server}/locations/{index}", AdminRoute::Location),
+        ];
+        for (path, route) in routes {
+            router
+                .insert(path, route)
+                .expect("admin routes should not conflict");
+        }
+
+        Self {
+            server_map,
+            allowed_ips: admin_toml.allowed_ips.iter().cloned().collect(),
+            router,
+        }
+    }
+
+    async fn handle(&self, session: &mut ServerSession) -> Result<Response<Vec<u8>>, Error> {
+        let client_ip = session
+            .client_addr()
+            .and_then(|e| e.as_inet())
+            .map(|e| e.ip());
+        if !client_ip.is_some_and(|ip| self.allowed_ips.contains(&ip)) {
+            return Err(Error::Forbidden);
+        }
+
+        let method = session.req_header().method.clone();
+        let path = session.req_header().uri.path().to_owned();
+
+        let route_match = self
+            .router
+            .at(&path)
+            .map_err(|_| Error::RouteNotFound(path.clone()))?;
+        let route = *route_match.value;
+        let server_name = route_match.params.get("server").map(str::to_owned);
+        let index = route_match
+            .params
+            .get("index")
+            .map(|e| {
+                e.parse::<usize>()
+                    .map_err(|err| Error::InvalidLocationIndex(err.to_string()))
+            })
+            .transpose()?;
+
+        match (route, method) {
+            (AdminRoute::Config, Method::GET) => Ok(json_response(
+                StatusCode::OK,
+                &self.server_map.config_toml().await,
+            )),
+            (AdminRoute::Servers, Method::GET) => {
+                let mut servers: Vec<ServerSummaryView> = self
+                    .server_map
+                    .servers
+                    .iter()
+                    .map(|e| ServerSummaryView::from(e.value().as_ref()))
+                    .collect();
+                servers.sort_by(|a, b| a.name.cmp(&b.name));
+                Ok(json_response(StatusCode::OK, &servers))
+            }
+            (AdminRoute::Server, Method::GET) => {
+                let server_name = server_name.unwrap_or_default();
+                let server = self
+                    .server_map
+                    .servers
+                    .get(&server_name)
+                    .map(|e| ServerView::from(e.value().as_ref()))
+                    .ok_or(Error::ServerMap(ServerMapError::ServerNotFound(
+                        server_name,
+                    )))?;
+                Ok(json_response(StatusCode::OK, &server))
+            }
+            (AdminRoute::Server, Method::PUT) => {
+                let server_toml: ServerToml = read_json_body(session).await?;
+                if Some(&server_toml.name) != server_name.as_ref() {
+                    return Err(Error::ServerNameMismatch);
+                }
+                info!("admin api: upserting server {}", server_toml.name);
+                self.server_map.upsert_server(server_toml).await?;
+                Ok(empty_response(StatusCode::NO_CONTENT))
+            }
+            (AdminRoute::Server, Method::DELETE) => {
+                let server_name = server_name.unwrap_or_default();
+                info!("admin api: removing server {server_name}");
+                self.server_map.remove_server(&server_name).await?;
+                Ok(empty_response(StatusCode::NO_CONTENT))
+            }
+            (AdminRoute::Maintenance, Method::GET) => {
+                let server_name = server_name.unwrap_or_default();
+                let maintenance = self
+                    .server_map
+                    .servers
+                    .get(&server_name)
+                    .map(|e| MaintenanceView {
+                        enabled: e.maintenance.is_enabled(),
+                    })
+                    .ok_or(Error::ServerMap(ServerMapError::ServerNotFound(
+                        server_name,
+                    )))?;
+                Ok(json_response(StatusCode::OK, &maintenance))
+            }
+            (AdminRoute::Maintenance, Method::PUT) => {
+                let server_name = server_name.unwrap_or_default();
+                let maintenance: MaintenanceView = read_json_body(session).await?;
+                info!(
+                    "admin api: setting maintenance of server {server_name} to {}",
+                    maintenance.enabled
+                );
+                self.server_map
+                    .set_maintenance(&server_name, maintenance.enabled)
+                    .await?;
+                Ok(empty_response(StatusCode::NO_CONTENT))
+            }
+            (AdminRoute::Locations, Method::GET) => {
+                let server_name = server_name.unwrap_or_default();
+                let locations = self
+                    .server_map
+                    .config_toml()
+                    .await
+                    .servers
+                    .into_iter()
+                    .find(|e| e.name == server_name)
+                    .map(|e| e.locations)
+                    .ok_or(Error::ServerMap(ServerMapError::ServerNotFound(
+                        server_name,
+                    )))?;
+                Ok(json_response(StatusCode::OK, &locations))
+            }
+            (AdminRoute::Locations, Method::POST) | (AdminRoute::Location, Method::PUT) => {
+                let server_name = server_name.unwrap_or_default();
+                let location_toml: LocationToml = read_json_body(session).await?;
+                info!("admin api: upserting location {index:?} in server {server_name}");
+                self.server_map
+                    .upsert_location(&server_name, index, location_toml)
+                    .await?;
+                Ok(empty_response(StatusCode::NO_CONTENT))
+            }
+            (AdminRoute::Location, Method::DELETE) => {
+                let server_name = server_name.unwrap_or_default();
+                let index = index.unwrap_or_default();
+                info!("admin api: removing location {index} from server {server_name}");
+                self.server_map.remove_location(&server_name, index).await?;
+                Ok(empty_response(StatusCode::NO_CONTENT))
+            }
+            (_, method) => Err(Error::MethodNotAllowed(method.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        match self.handle(session).await {
+            Ok(e) => e,
+            Err(err) => {
+                debug!("admin api error: {err}");
+                json_response(
+                    err.status_code(),
+                    &ErrorBody {
+                        error: err.to_string(),
+                    },
+                )
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: String,
+}
+
+async fn read_json_body<T: DeserializeOwned>(session: &mut ServerSession) -> Result<T, Error> {
+    let mut body = BytesMut::new();
+    while let Some(chunk) = session
+        .read_request_body()
+        .await
+        .map_err(|e| Error::InvalidBody(e.to_string()))?
+    {
+        if body.len() + chunk.len() > MAX_BODY_BYTES {
+            return Err(Error::BodyTooLarge(MAX_BODY_BYTES));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    serde_json::from_slice(&body).map_err(|e| Error::InvalidBody(e.to_string()))
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> Response<Vec<u8>> {
+    let body = serde_json::to_vec(body).unwrap();
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
+
+fn empty_response(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_LENGTH, 0)
+        .body(Vec::new())
+        .unwrap()
+}
@@ -0,0 +1,59 @@
+use http::StatusCode;
+use thiserror::Error;
+
+use crate::server_map::server::Error as ServerError;
+use crate::server_map::server_map::Error as ServerMapError;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("client ip is not allowed to use the admin api")]
+    Forbidden,
+
+    #[error("no admin route for {0}")]
+    RouteNotFound(String),
+
+    #[error("method {0} is not allowed on this route")]
+    MethodNotAllowed(String),
+
+    #[error("invalid request body => {0}")]
+    InvalidBody(String),
+
+    #[error("request body is larger than {0} bytes")]
+    BodyTooLarge(usize),
+
+    #[error("invalid location index => {0}")]
+    InvalidLocationIndex(String),
+
+    #[error("the server name in the path doesnt match the one in the body")]
+    ServerNameMismatch,
+
+    #[error("{0}")]
+    ServerMap(#[from] ServerMapError),
+}
+
+impl Error {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Forbidden => StatusCode::FORBIDDEN,
+            Error::RouteNotFound(_) => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Error::BodyTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::InvalidBody(_) | Error::InvalidLocationIndex(_) | Error::ServerNameMismatch => {
+                StatusCode::BAD_REQUEST
+            }
+            Error::ServerMap(
+                ServerMapError::InvalidConfig(_) | ServerMapError::InvalidDownstreamHost(_),
+            ) => StatusCode::BAD_REQUEST,
+            Error::ServerMap(
+                ServerMapError::ServerNotFound(_) | ServerMapError::LocationNotFound(_, _),
+            ) => StatusCode::NOT_FOUND,
+            Error::ServerMap(
+                ServerMapError::BuildTimedOut(_)
+                | ServerMapError::FailedToBuildServer(ServerError::PublicPemTimeout(_)),
+            ) => StatusCode::GATEWAY_TIMEOUT,
+            Error::ServerMap(ServerMapError::FailedToBuildServer(_)) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
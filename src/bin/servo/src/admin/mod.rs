@@ -0,0 +1,8 @@
+mod admin_app;
+pub use admin_app::AdminApp;
+
+mod server_view;
+pub use server_view::{MaintenanceView, ServerSummaryView, ServerView};
+
+mod error;
+pub use error::Error;
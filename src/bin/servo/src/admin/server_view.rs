@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config_toml::HashKeyToml,
+    server_map::{ProxyPass, Server, Upstream},
+};
+
+#[derive(Serialize, Debug)]
+pub struct ServerSummaryView {
+    pub name: String,
+    pub downstream_hosts: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServerView {
+    pub name: String,
+    pub downstream_hosts: Vec<String>,
+    pub maintenance: bool,
+    pub routes: Vec<UpstreamView>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MaintenanceView {
+    pub enabled: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct UpstreamView {
+    pub endpoint: String,
+    pub reroute_template: Option<String>,
+    pub url_concat_suffix: String,
+    pub blacklisted_endpoints: Vec<String>,
+    pub max_requests_per_sec: Option<isize>,
+    pub cache_time_secs: Option<u64>,
+    pub jwt_required: Option<bool>,
+    pub jwt_allowed_roles: Option<Vec<String>>,
+    pub load_balancing: &'static str,
+    pub circuit_breaker_open: Option<bool>,
+    pub hash_key: Option<HashKeyToml>,
+    pub backends: Vec<BackendView>,
+    pub variants: Vec<VariantView>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct VariantView {
+    pub name: String,
+    pub percent: u32,
+    pub load_balancing: &'static str,
+    pub circuit_breaker_open: Option<bool>,
+    pub backends: Vec<BackendView>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct BackendView {
+    pub addr: String,
+    pub weight: usize,
+    pub backup: bool,
+    pub healthy: bool,
+    pub ejected: bool,
+    pub active_connections: usize,
+    pub tls: bool,
+}
+
+impl From<&Server> for ServerSummaryView {
+    fn from(server: &Server) -> Self {
+        Self {
+            name: server.name.clone(),
+            downstream_hosts: server.downstream_hosts.clone(),
+        }
+    }
+}
+
+impl From<&Server> for ServerView {
+    fn from(server: &Server) -> Self {
+        Self {
+            name: server.name.clone(),
+            downstream_hosts: server.downstream_hosts.clone(),
+            maintenance: server.maintenance.is_enabled(),
+            routes: server
+                .upstreams
+                .iter()
+                .map(|(endpoint, upstream)| UpstreamView::new(endpoint, upstream))
+                .collect(),
+        }
+    }
+}
+
+impl UpstreamView {
+    fn new(endpoint: &str, upstream: &Upstream) -> Self {
+        let mut blacklisted_endpoints: Vec<String> =
+            upstream.blacklisted_endpoints.iter().cloned().collect();
+        blacklisted_endpoints.sort();
+
+        let jwt_allowed_roles = upstream
+            .auth
+            .as_ref()
+            .and_then(|e| e.jwt_auth_roles.as_ref())
+            .map(|roles| {
+                let mut roles: Vec<String> = roles.iter().cloned().collect();
+                roles.sort();
+                roles
+            });
+
+        Self {
+            endpoint: endpoint.to_owned(),
+            reroute_template: upstream.reroute_template.clone(),
+            url_concat_suffix: upstream.url_concat_suffix.clone(),
+            blacklisted_endpoints,
+            max_requests_per_sec: upstream.rate_limiter.as_ref().map(|e| e.max_req_sec()),
+            cache_time_secs: upstream.cache.as_ref().map(|e| e.cache_time_secs),
+            jwt_required: upstream.auth.as_ref().map(|e| e.jwt_required),
+            jwt_allowed_roles,
+            load_balancing: upstream.proxy_pass.load_balancing(),
+            circuit_breaker_open: upstream
+                .proxy_pass
+                .circuit_breaker
+                .as_ref()
+                .map(|e| e.is_open()),
+            hash_key: upstream.proxy_pass.hash_key.clone(),
+            backends: backend_views(&upstream.proxy_pass),
+            variants: upstream
+                .traffic_split
+                .iter()
+                .flat_map(|e| e.variants.iter())
+                .map(|e| VariantView {
+                    name: e.name.clone(),
+                    percent: e.percent,
+                    load_balancing: e.proxy_pass.load_balancing(),
+                    circuit_breaker_open: e
+                        .proxy_pass
+                        .circuit_breaker
+                        .as_ref()
+                        .map(|e| e.is_open()),
+                    backends: backend_views(&e.proxy_pass),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn backend_views(proxy_pass: &ProxyPass) -> Vec<BackendView> {
+    proxy_pass
+        .backends_health()
+        .into_iter()
+        .map(|e| BackendView {
+            addr: e.addr,
+            weight: e.weight,
+            backup: e.backup,
+            healthy: e.healthy,
+            ejected: e.ejected,
+            active_connections: e.active_connections,
+            tls: e.tls,
+        })
+        .collect()
+}
@@ -0,0 +1,61 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::{task::JoinHandle, time::interval};
+
+use crate::{config_toml::GatewayConfigToml, tls::Certificates};
+
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+// polls the cert and key files of every tls listener and swaps in the pairs
+// that changed, certificates close to expiring are logged once a day
+#[derive(Debug)]
+pub struct CertificateWatcher {
+    task_handle: JoinHandle<()>,
+}
+
+impl CertificateWatcher {
+    pub fn spawn(gateway_config: &GatewayConfigToml, certificates: Vec<Arc<Certificates>>) -> Self {
+        let check_duration =
+            Duration::from_millis(gateway_config.config_check_duration.unwrap_or(5000));
+        let expiry_warning = gateway_config.cert_expiry_warning();
+
+        let task_handle = tokio::spawn(background_certificate_watch(
+            certificates,
+            check_duration,
+            expiry_warning,
+        ));
+
+        Self { task_handle }
+    }
+}
+
+impl Drop for CertificateWatcher {
+    fn drop(&mut self) {
+        self.task_handle.abort();
+    }
+}
+
+async fn background_certificate_watch(
+    certificates: Vec<Arc<Certificates>>,
+    check_duration: Duration,
+    expiry_warning: Duration,
+) {
+    let mut reload_interval = interval(check_duration);
+    // the first tick fires right away, so expiring certs are logged on startup
+    let mut expiry_interval = interval(EXPIRY_CHECK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = reload_interval.tick() => {
+                for e in &certificates {
+                    e.reload_changed();
+                }
+            }
+            _ = expiry_interval.tick() => {
+                for e in &certificates {
+                    e.warn_expiring(expiry_warning);
+                }
+            }
+        }
+    }
+}
@@ -1,72 +1,524 @@
-use std::{collections::HashSet, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
 
 use log::Level;
 use serde::{Deserialize, Serialize};
 use servo_toml::FormatValidate;
 use url::Url;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+use crate::server_map::{
+    ClientCertRule, Compression, Cors, HeaderRules, HostPattern, Hsts, HttpsRedirect, Maintenance,
+    Redirect, StaticFiles, StaticResponse,
+};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ConfigToml {
     pub config: GatewayConfigToml,
     pub servers: Vec<ServerToml>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct GatewayConfigToml {
     pub gateway_name: String,
     pub listens: Vec<SocketAddr>,
+    // certificates for a tls listener on 0.0.0.0:443, tls_listens takes more settings
     pub tls: Option<Vec<TLSToml>>,
+    pub tls_listens: Option<Vec<TlsListenToml>>,
+    // certificates closer than this to their notAfter are logged and flagged
+    // in the metrics, defaults to 30
+    pub cert_expiry_warning_days: Option<u64>,
     pub log_level: Level,
+    pub config_check_duration: Option<u64>,
+    pub admin: Option<AdminToml>,
+    pub metrics: Option<MetricsToml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AdminToml {
+    pub listen: SocketAddr,
+    pub allowed_ips: Vec<IpAddr>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MetricsToml {
+    pub listen: SocketAddr,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct TLSToml {
     pub cert_path: PathBuf,
     pub key_path: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TlsListenToml {
+    pub listen: SocketAddr,
+    // picked by sni, the first one is served when nothing matches
+    pub certificates: Vec<TLSToml>,
+    // "1.2" or "1.3", defaults to 1.2
+    pub min_version: Option<String>,
+    // openssl names, TLS_* suites are used for tls 1.3 and the rest for 1.2,
+    // defaults to the mozilla intermediate list
+    pub cipher_suites: Option<Vec<String>>,
+    // "h2" and "http/1.1" in order of preference, defaults to both
+    pub alpn: Option<Vec<String>>,
+    // defaults to true
+    pub session_tickets: Option<bool>,
+    // asks clients for a certificate signed by the ca bundle
+    pub client_auth: Option<ClientAuthToml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ClientAuthToml {
+    pub ca_path: PathBuf,
+    // rejects handshakes without a certificate, defaults to false where
+    // a certificate is only checked when the client sends one
+    pub required: Option<bool>,
+}
+
+impl GatewayConfigToml {
+    pub fn cert_expiry_warning(&self) -> Duration {
+        Duration::from_secs(self.cert_expiry_warning_days.unwrap_or(30) * 24 * 60 * 60)
+    }
+
+    // the tls listeners with the plain tls certificates as one on 0.0.0.0:443
+    pub fn tls_listeners(&self) -> Vec<TlsListenToml> {
+        let mut tls_listeners = self.tls_listens.clone().unwrap_or_default();
+        if let Some(certificates) = &self.tls {
+            tls_listeners.push(TlsListenToml {
+                listen: SocketAddr::from(([0, 0, 0, 0], 443)),
+                certificates: certificates.clone(),
+                min_version: None,
+                cipher_suites: None,
+                alpn: None,
+                session_tickets: None,
+                client_auth: None,
+            });
+        }
+        tls_listeners
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ServerToml {
     pub name: String,
+    // exact hosts, *.example.com wildcards or ~regexes, matched without the port
     pub downstream_hosts: Vec<String>,
+    // answers every host no other server matches
+    pub default_server: Option<bool>,
     pub auth: Option<AuthToml>,
     pub cache: Option<CacheToml>,
     pub locations: Vec<LocationToml>,
+    // bodies for gateway errors and upstream 5xx responses
+    pub error_pages: Option<Vec<ErrorPageToml>>,
+    // applied before the rules of the location
+    pub headers: Option<HeaderRulesToml>,
+    // used by the locations without a cors table of their own
+    pub cors: Option<CorsToml>,
+    pub maintenance: Option<MaintenanceToml>,
+    // only used when the gateway has tls
+    pub https_redirect: Option<HttpsRedirectToml>,
+    pub hsts: Option<HstsToml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HttpsRedirectToml {
+    // 301, 302, 307 or 308, defaults to 301
+    pub status: Option<u16>,
+    // the port of the tls listener, defaults to 443
+    pub https_port: Option<u16>,
+    // exact paths or prefixes ending in *, still served over plain http
+    pub exempt_paths: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HstsToml {
+    pub max_age_secs: u64,
+    pub include_subdomains: Option<bool>,
+    // needs include_subdomains and a max_age_secs of at least a year
+    pub preload: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct MaintenanceToml {
+    // can be switched at runtime through the admin api
+    pub enabled: Option<bool>,
+    // still reach the backends during maintenance
+    pub allowed_ips: Option<Vec<IpAddr>>,
+    pub retry_after_secs: Option<u64>,
+    // without a body the server's 503 error page is used
+    pub content_type: Option<String>,
+    pub body: Option<String>,
+    pub body_path: Option<PathBuf>,
+}
+
+// origins are exact, "*", a "https://*.example.com" wildcard or a ~regex
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CorsToml {
+    pub allowed_origins: Vec<String>,
+    // defaults to GET, HEAD and POST
+    pub allowed_methods: Option<Vec<String>>,
+    // defaults to the headers the preflight asks for
+    pub allowed_headers: Option<Vec<String>>,
+    pub exposed_headers: Option<Vec<String>>,
+    pub allow_credentials: Option<bool>,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HeaderRulesToml {
+    pub request: Option<HeaderActionsToml>,
+    pub response: Option<HeaderActionsToml>,
+}
+
+// values are templates, see HeaderTemplate for the placeholders
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HeaderActionsToml {
+    pub remove: Option<Vec<String>>,
+    pub set: Option<HashMap<String, String>>,
+    pub add: Option<HashMap<String, String>>,
+}
+
+// the body is a template, {status}, {reason} and {message} are filled in
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ErrorPageToml {
+    pub status: StatusRangeToml,
+    pub content_type: Option<String>,
+    pub body: Option<String>,
+    pub body_path: Option<PathBuf>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct CacheToml {
     pub url: Url,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct EndpointToml {
     pub path: String,
     pub reroute: Option<String>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct LocationToml {
     pub endpoints: Vec<EndpointToml>,
     pub blacklisted_endpoints: Option<Vec<String>>,
     pub max_requests_per_sec: Option<usize>,
-    pub proxy_passes: Vec<String>,
+    // left out for locations that respond, redirect or serve files on their own
+    #[serde(default)]
+    pub proxy_passes: Vec<ProxyPassToml>,
     pub health_check: Option<bool>,
     pub health_check_frequency: Option<u64>,
+    // switches the health check from a tcp connect to an http request
+    pub http_health_check: Option<HttpHealthCheckToml>,
     pub requires_jwt: Option<bool>,
     pub cacheable: Option<bool>,
     pub cache_time_secs: Option<u64>,
     pub jwt_allowed_roles: Option<Vec<String>>,
+    pub load_balancing: Option<LoadBalancingToml>,
+    pub hash_key: Option<HashKeyToml>,
+    pub outlier_detection: Option<OutlierDetectionToml>,
+    pub circuit_breaker: Option<CircuitBreakerToml>,
+    pub retry: Option<RetryToml>,
+    pub timeouts: Option<TimeoutsToml>,
+    // tls to every backend of the location, a backend can override it
+    pub tls: Option<UpstreamTlsToml>,
+    // locations sharing an endpoint are tried in order, the first whose
+    // matches all hold takes the request
+    pub matches: Option<RequestMatchToml>,
+    // sends a share of the traffic to other backend groups, the location's
+    // own proxy_passes are the default variant and take the rest
+    pub split: Option<TrafficSplitToml>,
+    // copies requests to a shadow group of backends, their responses are dropped
+    pub mirror: Option<MirrorToml>,
+    pub headers: Option<HeaderRulesToml>,
+    pub cors: Option<CorsToml>,
+    pub compression: Option<CompressionToml>,
+    // answers every request itself instead of proxying it
+    pub respond: Option<StaticResponseToml>,
+    pub redirect: Option<RedirectToml>,
+    pub static_files: Option<StaticFilesToml>,
+    // needs a verified client certificate from a tls listener with client_auth
+    pub client_cert: Option<ClientCertToml>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+// a certificate matching any entry is let in, any verified one without entries
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ClientCertToml {
+    pub common_names: Option<Vec<String>>,
+    pub sans: Option<Vec<String>>,
+    // sha256 of the certificate in hex, colons are allowed
+    pub fingerprints: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StaticFilesToml {
+    pub root: PathBuf,
+    // the endpoint path param holding the file path, defaults to the only one
+    pub path_param: Option<String>,
+    // tried in order for directories, defaults to index.html
+    pub index: Option<Vec<String>>,
+    // missing paths without an extension get the first index of the root
+    pub spa_fallback: Option<bool>,
+    // sends file.br / file.gz instead of file when the client accepts it, defaults to true
+    pub precompressed: Option<bool>,
+    pub cache_control: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct StaticResponseToml {
+    // defaults to 200
+    pub status: Option<u16>,
+    pub headers: Option<HashMap<String, String>>,
+    // defaults to text/plain when there is a body
+    pub content_type: Option<String>,
+    pub body: Option<String>,
+    pub body_path: Option<PathBuf>,
+}
+
+// {param} and {*param} in the target are filled from the endpoint path
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RedirectToml {
+    pub to: String,
+    // 301, 302, 307 or 308, defaults to 302
+    pub status: Option<u16>,
+    // appends the request query to the target, defaults to true
+    pub keep_query: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CompressionToml {
+    // gzip, br and zstd, in order of preference
+    pub algorithms: Vec<String>,
+    // capped at 9 for gzip, 11 for br and 22 for zstd, defaults to 6
+    pub level: Option<u32>,
+    // smaller responses are sent as is, defaults to 1024
+    pub min_size_bytes: Option<usize>,
+    // content type prefixes, defaults to text/, json, javascript, xml and svg
+    pub content_types: Option<Vec<String>>,
+    // caches a compressed copy per encoding instead of compressing each hit
+    pub cache_per_encoding: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MirrorToml {
+    pub proxy_passes: Vec<ProxyPassToml>,
+    // the share of requests copied, defaults to 100
+    pub percent: Option<u32>,
+    // larger bodies are not mirrored, defaults to 64kb
+    pub max_body_bytes: Option<usize>,
+    // sets X-Gateway-Shadow: 1 on the copies, defaults to true
+    pub tag: Option<bool>,
+    pub timeout_ms: Option<u64>,
+}
+
+// the variant name that picks the location's own proxy_passes
+pub const DEFAULT_VARIANT: &str = "default";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TrafficSplitToml {
+    // keeps a user on one variant, defaults to the client ip
+    pub sticky_key: Option<HashKeyToml>,
+    // a request with this header set to a variant name goes to that variant,
+    // defaults to x-servo-variant
+    pub override_header: Option<String>,
+    pub variants: Vec<VariantToml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VariantToml {
+    pub name: String,
+    // the share of requests in percent
+    pub percent: u32,
+    pub proxy_passes: Vec<ProxyPassToml>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RequestMatchToml {
+    pub methods: Option<Vec<String>>,
+    pub headers: Option<Vec<KeyValueMatchToml>>,
+    pub query: Option<Vec<KeyValueMatchToml>>,
+    pub cookies: Option<Vec<KeyValueMatchToml>>,
+}
+
+// without a value only the presence of the key is checked
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct KeyValueMatchToml {
+    pub name: String,
+    pub value: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct UpstreamTlsToml {
+    // defaults to the host of the proxy pass address
+    pub sni: Option<String>,
+    pub ca_path: Option<PathBuf>,
+    pub client_cert_path: Option<PathBuf>,
+    pub client_key_path: Option<PathBuf>,
+    // skips certificate and hostname verification, dev only
+    pub verify: Option<bool>,
+    // offers h2 over alpn, falling back to http/1.1
+    pub http2: Option<bool>,
+}
+
+// every timeout is in milliseconds, total_ms covers the whole request
+// including retries and the response body
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TimeoutsToml {
+    pub connect_ms: Option<u64>,
+    pub tls_handshake_ms: Option<u64>,
+    pub read_ms: Option<u64>,
+    pub write_ms: Option<u64>,
+    pub idle_ms: Option<u64>,
+    pub total_ms: Option<u64>,
+}
+
+// max_attempts counts the first try, non idempotent methods are only
+// retried when the backend could not be connected to
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RetryToml {
+    pub max_attempts: usize,
+    pub retry_on: Option<Vec<RetryOnToml>>,
+    pub retry_on_status: Option<Vec<u16>>,
+    // doubled after every retry, up to max_backoff_ms
+    pub backoff_ms: Option<u64>,
+    pub max_backoff_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOnToml {
+    ConnectError,
+    Timeout,
+}
+
+// ejects a backend after consecutive 5xx / connect failures, every repeated
+// ejection doubles the ejection time up to max_ejection_ms
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct OutlierDetectionToml {
+    pub consecutive_failures: Option<usize>,
+    pub base_ejection_ms: Option<u64>,
+    pub max_ejection_ms: Option<u64>,
+}
+
+// fails the whole location fast with a 503 once the error ratio of a window
+// passes error_ratio, then lets a single probe request through after open_ms
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CircuitBreakerToml {
+    pub error_ratio: f64,
+    pub min_requests: Option<u64>,
+    pub window_ms: Option<u64>,
+    pub open_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HttpHealthCheckToml {
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub host: Option<String>,
+    pub expected_status: Option<StatusRangeToml>,
+    pub body_contains: Option<String>,
+    pub timeout_ms: Option<u64>,
+    // consecutive checks needed to mark a backend healthy / unhealthy
+    pub rise: Option<usize>,
+    pub fall: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct StatusRangeToml {
+    pub min: u16,
+    pub max: u16,
+}
+
+// either a plain "host:port" or a table with the extra backend options
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum ProxyPassToml {
+    Addr(String),
+    Backend(BackendToml),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BackendToml {
+    pub addr: String,
+    pub weight: Option<usize>,
+    // only sent traffic when no primary backend can take the request
+    pub backup: Option<bool>,
+    pub max_connections: Option<usize>,
+    pub tls: Option<UpstreamTlsToml>,
+}
+
+impl ProxyPassToml {
+    pub fn addr(&self) -> &str {
+        match self {
+            ProxyPassToml::Addr(addr) => addr,
+            ProxyPassToml::Backend(backend) => &backend.addr,
+        }
+    }
+
+    pub fn weight(&self) -> usize {
+        match self {
+            ProxyPassToml::Addr(_) => 1,
+            ProxyPassToml::Backend(backend) => backend.weight.unwrap_or(1),
+        }
+    }
+
+    pub fn backup(&self) -> bool {
+        match self {
+            ProxyPassToml::Addr(_) => false,
+            ProxyPassToml::Backend(backend) => backend.backup.unwrap_or(false),
+        }
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        match self {
+            ProxyPassToml::Addr(_) => None,
+            ProxyPassToml::Backend(backend) => backend.max_connections,
+        }
+    }
+
+    pub fn tls(&self) -> Option<&UpstreamTlsToml> {
+        match self {
+            ProxyPassToml::Addr(_) => None,
+            ProxyPassToml::Backend(backend) => backend.tls.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancingToml {
+    #[default]
+    RoundRobin,
+    Random,
+    LeastConnections,
+    Ketama,
+}
+
+// what a ketama location hashes to pick a backend, also the sticky key of a split
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HashKeyToml {
+    #[default]
+    ClientIp,
+    Header(String),
+    Cookie(String),
+    PathParam(String),
+    JwtSub,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct AuthToml {
     #[serde(flatten)]
     pub public_pem_location: PublicPemLocationToml,
     pub check_duration: u64,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum PublicPemLocationToml {
     PublicPemHttpUrl(Url),
@@ -78,6 +530,7 @@ impl Default for ConfigToml {
         let server_1 = ServerToml {
             name: "test".into(),
             downstream_hosts: vec!["someaddress.com".into(), "0.0.0.0:54321".into()],
+            default_server: None,
             auth: Some(AuthToml {
                 public_pem_location: PublicPemLocationToml::PublicPemHttpUrl(
                     Url::parse("http://0.0.0.0:25025/public_pem").unwrap(),
@@ -98,23 +551,52 @@ impl Default for ConfigToml {
                 blacklisted_endpoints: Some(vec!["/auth/public_pem".into()]),
                 health_check: Some(true),
                 health_check_frequency: Some(3000),
-                proxy_passes: vec!["192.168.1.103:8080".parse().unwrap()],
+                http_health_check: None,
+                proxy_passes: vec![ProxyPassToml::Addr("192.168.1.103:8080".into())],
                 max_requests_per_sec: Some(10),
                 requires_jwt: Some(true),
                 jwt_allowed_roles: Some(vec!["user".into()]),
                 cacheable: Some(true),
                 cache_time_secs: Some(60 * 60),
+                load_balancing: None,
+                hash_key: None,
+                outlier_detection: None,
+                circuit_breaker: None,
+                retry: None,
+                timeouts: None,
+                tls: None,
+                matches: None,
+                split: None,
+                mirror: None,
+                headers: None,
+                cors: None,
+                compression: None,
+                respond: None,
+                redirect: None,
+                static_files: None,
+                client_cert: None,
             }],
             cache: Some(CacheToml {
                 url: Url::parse("redis://0.0.0.0:6379").unwrap(),
             }),
+            error_pages: None,
+            headers: None,
+            cors: None,
+            maintenance: None,
+            https_redirect: None,
+            hsts: None,
         };
 
         let config = GatewayConfigToml {
             gateway_name: "give me a name vro".into(),
             listens: vec!["0.0.0.0:54321".parse().unwrap()],
             tls: None,
+            tls_listens: None,
+            cert_expiry_warning_days: None,
             log_level: Level::Info,
+            config_check_duration: Some(5000),
+            admin: None,
+            metrics: None,
         };
 
         Self {
@@ -130,20 +612,110 @@ impl FormatValidate for ConfigToml {
             return Err("Duplicate proxy listen addresses!".into());
         }
 
+        if let Some(admin) = &self.config.admin
+            && self.config.listens.contains(&admin.listen)
+        {
+            return Err("The admin listen address is also a proxy listen address!".into());
+        }
+
+        if let Some(metrics) = &self.config.metrics
+            && (self.config.listens.contains(&metrics.listen)
+                || self
+                    .config
+                    .admin
+                    .as_ref()
+                    .is_some_and(|admin| admin.listen == metrics.listen))
+        {
+            return Err("The metrics listen address is already used by another listener!".into());
+        }
+
+        let tls_listeners = self.config.tls_listeners();
+        let mut all_listens = self.config.listens.clone();
+        all_listens.extend(self.config.admin.as_ref().map(|e| e.listen));
+        all_listens.extend(self.config.metrics.as_ref().map(|e| e.listen));
+        all_listens.extend(tls_listeners.iter().map(|e| e.listen));
+        if has_duplicates(&all_listens) {
+            return Err("A tls listen address is already used by another listener!".into());
+        }
+        for tls_listener in &tls_listeners {
+            validate_tls_listener(tls_listener)?;
+        }
+
         let upstream_names: Vec<String> = self.servers.iter().map(|e| e.name.clone()).collect();
         if has_duplicates(&upstream_names) {
             return Err("2 or more servers have the same name!".into());
         }
 
-        let all_downstream_hosts: Vec<String> = self
-            .servers
-            .iter()
-            .flat_map(|upstream| upstream.downstream_hosts.clone())
-            .collect();
+        // hosts are compared the way requests are matched, without the port
+        let mut all_downstream_hosts = Vec::new();
+        for downstream_host in self.servers.iter().flat_map(|e| &e.downstream_hosts) {
+            let normalized = match HostPattern::parse(downstream_host).map_err(|e| e.to_string())? {
+                HostPattern::Exact(host) => host.to_string(),
+                HostPattern::Wildcard(suffix) => format!("*{suffix}"),
+                HostPattern::Regex(regex) => format!("~{regex}"),
+            };
+            all_downstream_hosts.push(normalized);
+        }
         if has_duplicates(&all_downstream_hosts) {
             return Err("Duplicate downstream hosts found across servers!".into());
         }
 
+        for headers in self.servers.iter().filter_map(|e| e.headers.as_ref()) {
+            HeaderRules::from_header_rules_toml(headers).map_err(|e| e.to_string())?;
+        }
+
+        for cors in self.servers.iter().filter_map(|e| e.cors.as_ref()) {
+            validate_cors(cors)?;
+        }
+
+        for maintenance in self.servers.iter().filter_map(|e| e.maintenance.as_ref()) {
+            if maintenance.body.is_some() && maintenance.body_path.is_some() {
+                return Err("A maintenance page has either a body or a body_path!".into());
+            }
+            Maintenance::from_maintenance_toml(Some(maintenance)).map_err(|e| e.to_string())?;
+        }
+
+        for server in &self.servers {
+            if (server.https_redirect.is_some() || server.hsts.is_some())
+                && tls_listeners.is_empty()
+            {
+                return Err(format!(
+                    "Server {} has an https_redirect or hsts but the gateway has no tls!",
+                    server.name
+                ));
+            }
+            if let Some(https_redirect) = &server.https_redirect {
+                HttpsRedirect::from_https_redirect_toml(https_redirect)
+                    .map_err(|e| e.to_string())?;
+            }
+            if let Some(hsts) = &server.hsts {
+                Hsts::from_hsts_toml(hsts).map_err(|e| e.to_string())?;
+            }
+        }
+
+        let default_servers = self
+            .servers
+            .iter()
+            .filter(|e| e.default_server.unwrap_or(false))
+            .count();
+        if default_servers > 1 {
+            return Err("Only one server can be the default_server!".into());
+        }
+
+        let error_pages = self
+            .servers
+            .iter()
+            .flat_map(|server| server.error_pages.iter().flatten());
+        for error_page in error_pages {
+            let StatusRangeToml { min, max } = error_page.status;
+            if min < 400 || max > 599 || min > max {
+                return Err("An error page status must be a range within 400-599!".into());
+            }
+            if error_page.body.is_some() == error_page.body_path.is_some() {
+                return Err("An error page needs exactly one of body or body_path!".into());
+            }
+        }
+
         let all_location_endpoint_patterns: Vec<EndpointToml> = self
             .servers
             .iter()
@@ -159,20 +731,149 @@ impl FormatValidate for ConfigToml {
             return Err("Not all endpoint patterns start with '/'!".into());
         }
 
+        for location in self.servers.iter().flat_map(|e| e.locations.iter()) {
+            if location.hash_key.is_some()
+                && location.load_balancing != Some(LoadBalancingToml::Ketama)
+            {
+                return Err("A hash_key is only used with ketama load balancing!".into());
+            }
+
+            let answers = [
+                location.respond.is_some(),
+                location.redirect.is_some(),
+                location.static_files.is_some(),
+            ];
+            if answers.iter().filter(|e| **e).count() > 1 {
+                return Err(
+                    "A location can only have one of respond, redirect or static_files!".into(),
+                );
+            }
+            if answers.contains(&true) {
+                if !location.proxy_passes.is_empty()
+                    || location.split.is_some()
+                    || location.mirror.is_some()
+                {
+                    return Err("A location that responds, redirects or serves static_files has no proxy_passes, split or mirror!".into());
+                }
+            } else {
+                validate_proxy_passes(&location.proxy_passes)?;
+            }
+            if let Some(respond) = &location.respond {
+                if respond.body.is_some() && respond.body_path.is_some() {
+                    return Err("A static response has either a body or a body_path!".into());
+                }
+                StaticResponse::from_static_response_toml(respond).map_err(|e| e.to_string())?;
+            }
+            if let Some(redirect) = &location.redirect {
+                Redirect::from_redirect_toml(redirect).map_err(|e| e.to_string())?;
+            }
+            if let Some(static_files) = &location.static_files {
+                if let Some(path_param) = &static_files.path_param
+                    && !location.endpoints.iter().all(|e| {
+                        e.path.contains(&format!("{{{path_param}}}"))
+                            || e.path.contains(&format!("{{*{path_param}}}"))
+                    })
+                {
+                    return Err(format!(
+                        "Every endpoint serving static files needs the '{path_param}' path param!"
+                    ));
+                }
+                StaticFiles::from_static_files_toml(static_files).map_err(|e| e.to_string())?;
+            }
+            if let Some(client_cert) = &location.client_cert {
+                if !tls_listeners.iter().any(|e| e.client_auth.is_some()) {
+                    return Err(
+                        "A location with client_cert needs a tls listener with client_auth!".into(),
+                    );
+                }
+                ClientCertRule::from_client_cert_toml(client_cert).map_err(|e| e.to_string())?;
+            }
+            if let Some(split) = &location.split {
+                validate_traffic_split(split)?;
+            }
+            if let Some(mirror) = &location.mirror {
+                validate_proxy_passes(&mirror.proxy_passes)?;
+                if mirror.percent.is_some_and(|e| e > 100) {
+                    return Err("The mirror percent can be at most 100!".into());
+                }
+            }
+
+            if let Some(http_health_check) = &location.http_health_check {
+                validate_http_health_check(location, http_health_check)?;
+            }
+
+            if location
+                .outlier_detection
+                .as_ref()
+                .is_some_and(|e| e.consecutive_failures == Some(0))
+            {
+                return Err("Outlier detection consecutive_failures must be at least 1!".into());
+            }
+
+            if let Some(circuit_breaker) = &location.circuit_breaker
+                && !(circuit_breaker.error_ratio > 0.0 && circuit_breaker.error_ratio <= 1.0)
+            {
+                return Err("The circuit breaker error_ratio must be in (0, 1]!".into());
+            }
+
+            if let Some(retry) = &location.retry {
+                if retry.max_attempts == 0 {
+                    return Err("Retry max_attempts must be at least 1!".into());
+                }
+                if retry
+                    .retry_on_status
+                    .iter()
+                    .flatten()
+                    .any(|status| !(500..=599).contains(status))
+                {
+                    return Err("Retry retry_on_status only takes 5xx status codes!".into());
+                }
+            }
+
+            if let Some(tls) = &location.tls {
+                validate_upstream_tls(tls)?;
+            }
+
+            if let Some(headers) = &location.headers {
+                HeaderRules::from_header_rules_toml(headers).map_err(|e| e.to_string())?;
+            }
+
+            if let Some(cors) = &location.cors {
+                validate_cors(cors)?;
+            }
+
+            if let Some(compression) = &location.compression {
+                Compression::from_compression_toml(compression).map_err(|e| e.to_string())?;
+            }
+
+            if let Some(matches) = &location.matches {
+                validate_request_match(matches)?;
+            }
+        }
+
         for server_toml in &self.servers {
-            let endpoints: Vec<String> = server_toml
-                .locations
-                .iter()
-                .flat_map(|loc| loc.endpoints.iter())
-                .map(|e| &e.path)
-                .cloned()
-                .collect();
-
-            if has_duplicates(&endpoints) {
-                return Err(format!(
-                    "Duplicate endpoint patterns found in upstream '{}'",
-                    server_toml.name
-                ));
+            // an endpoint can be shared by locations with matches, but a
+            // location without matches takes every request so it has to be last
+            let mut catch_all_endpoints = HashSet::new();
+            for location in &server_toml.locations {
+                let endpoints: Vec<&String> = location.endpoints.iter().map(|e| &e.path).collect();
+                if has_duplicates(&endpoints) {
+                    return Err(format!(
+                        "Duplicate endpoint patterns found in upstream '{}'",
+                        server_toml.name
+                    ));
+                }
+                for endpoint in endpoints {
+                    if catch_all_endpoints.contains(endpoint) {
+                        return Err(format!(
+                            "Endpoint '{endpoint}' in upstream '{}' is unreachable, an earlier location without matches takes all of its requests",
+                            server_toml.name
+                        ));
+                    }
+                    if location.matches.is_none() {
+                        catch_all_endpoints.insert(endpoint);
+                    }
+                }
             }
         }
 
@@ -180,6 +881,169 @@ impl FormatValidate for ConfigToml {
     }
 }
 
+// the checks shared by the proxy passes of a location and of its variants
+fn validate_proxy_passes(proxy_passes: &[ProxyPassToml]) -> Result<(), String> {
+    if proxy_passes.iter().all(|e| e.backup()) {
+        return Err("Every location needs at least one non backup proxy pass!".into());
+    }
+
+    if proxy_passes
+        .iter()
+        .any(|e| e.weight() == 0 || e.max_connections() == Some(0))
+    {
+        return Err("Proxy pass weight and max_connections must be at least 1!".into());
+    }
+
+    for tls in proxy_passes.iter().filter_map(|e| e.tls()) {
+        validate_upstream_tls(tls)?;
+    }
+
+    let addrs: Vec<&str> = proxy_passes.iter().map(|e| e.addr()).collect();
+    if has_duplicates(&addrs) {
+        return Err("Duplicate proxy pass addresses found in a location!".into());
+    }
+
+    Ok(())
+}
+
+fn validate_tls_listener(tls_listener: &TlsListenToml) -> Result<(), String> {
+    if tls_listener.certificates.is_empty() {
+        return Err(format!(
+            "The tls listener {} needs at least one certificate!",
+            tls_listener.listen
+        ));
+    }
+
+    if let Some(min_version) = &tls_listener.min_version
+        && !["1.2", "1.3"].contains(&min_version.as_str())
+    {
+        return Err(format!(
+            "Invalid tls min_version '{min_version}', expected 1.2 or 1.3!"
+        ));
+    }
+
+    if let Some(alpn) = &tls_listener.alpn {
+        if alpn.is_empty() || has_duplicates(alpn) {
+            return Err("A tls alpn list needs unique protocols!".into());
+        }
+        if let Some(protocol) = alpn
+            .iter()
+            .find(|e| !["h2", "http/1.1"].contains(&e.as_str()))
+        {
+            return Err(format!(
+                "Invalid tls alpn protocol '{protocol}', expected h2 or http/1.1!"
+            ));
+        }
+    }
+
+    if tls_listener
+        .cipher_suites
+        .as_ref()
+        .is_some_and(|e| e.is_empty())
+    {
+        return Err("A tls cipher_suites list cant be empty!".into());
+    }
+
+    Ok(())
+}
+
+fn validate_cors(cors: &CorsToml) -> Result<(), String> {
+    // reflecting every origin with credentials lets any site make
+    // credentialed requests, the origins have to be listed instead
+    if cors.allow_credentials == Some(true) && cors.allowed_origins.iter().any(|e| e == "*") {
+        return Err("Cors allowed_origins cant contain \"*\" with allow_credentials!".into());
+    }
+    Cors::from_cors_toml(cors).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+fn validate_upstream_tls(tls: &UpstreamTlsToml) -> Result<(), String> {
+    if tls.client_cert_path.is_some() != tls.client_key_path.is_some() {
+        return Err("Upstream tls needs both client_cert_path and client_key_path!".into());
+    }
+    Ok(())
+}
+
+fn validate_traffic_split(split: &TrafficSplitToml) -> Result<(), String> {
+    if split.variants.is_empty() {
+        return Err("A split needs at least one variant!".into());
+    }
+
+    let names: Vec<&str> = split.variants.iter().map(|e| e.name.as_str()).collect();
+    if has_duplicates(&names) || names.contains(&DEFAULT_VARIANT) {
+        return Err(format!(
+            "Split variant names must be unique and not '{DEFAULT_VARIANT}'!"
+        ));
+    }
+
+    if split.variants.iter().map(|e| e.percent).sum::<u32>() > 100 {
+        return Err("The split variant percents add up to more than 100!".into());
+    }
+
+    if let Some(override_header) = &split.override_header
+        && http::HeaderName::from_bytes(override_header.as_bytes()).is_err()
+    {
+        return Err(format!(
+            "Invalid split override_header '{override_header}'!"
+        ));
+    }
+
+    for variant in &split.variants {
+        validate_proxy_passes(&variant.proxy_passes)?;
+    }
+
+    Ok(())
+}
+
+fn validate_request_match(request_match: &RequestMatchToml) -> Result<(), String> {
+    for method in request_match.methods.iter().flatten() {
+        if http::Method::from_bytes(method.as_bytes()).is_err() {
+            return Err(format!("Invalid match method '{method}'!"));
+        }
+    }
+
+    for header in request_match.headers.iter().flatten() {
+        if http::HeaderName::from_bytes(header.name.as_bytes()).is_err() {
+            return Err(format!("Invalid match header name '{}'!", header.name));
+        }
+    }
+
+    Ok(())
+}
+
+fn validate_http_health_check(
+    location: &LocationToml,
+    http_health_check: &HttpHealthCheckToml,
+) -> Result<(), String> {
+    if !location.health_check.unwrap_or(false) {
+        return Err("An http_health_check needs health_check = true!".into());
+    }
+
+    if let Some(method) = &http_health_check.method
+        && http::Method::from_bytes(method.as_bytes()).is_err()
+    {
+        return Err(format!("Invalid http health check method '{method}'!"));
+    }
+
+    if let Some(path) = &http_health_check.path
+        && !path.starts_with('/')
+    {
+        return Err("The http health check path must start with '/'!".into());
+    }
+
+    if let Some(StatusRangeToml { min, max }) = http_health_check.expected_status
+        && (min < 100 || max > 599 || min > max)
+    {
+        return Err("The http health check expected_status is not a valid range!".into());
+    }
+
+    if http_health_check.rise == Some(0) || http_health_check.fall == Some(0) {
+        return Err("The http health check rise and fall must be at least 1!".into());
+    }
+
+    Ok(())
+}
+
 fn has_duplicates<T: Eq + std::hash::Hash>(vec: &[T]) -> bool {
     let mut seen = HashSet::new();
     for item in vec {
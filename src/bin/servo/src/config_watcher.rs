@@ -0,0 +1,98 @@
+use std::{fs, path::PathBuf, sync::Arc, time::Duration, time::SystemTime};
+
+use log::{error, info};
+use servo_toml::read_toml_file;
+use tokio::{
+    signal::unix::{SignalKind, signal},
+    task::JoinHandle,
+    time::sleep,
+};
+
+use crate::{ConfigToml, config_toml::GatewayConfigToml, server_map::ServerMap};
+
+// watches the config file (mtime polling + SIGHUP) and hot swaps the servers
+// in the server map, the [config] table itself is only read on startup.
+// changes made through the admin api are overwritten by the next reload
+#[derive(Debug)]
+pub struct ConfigWatcher {
+    task_handle: JoinHandle<()>,
+}
+
+impl ConfigWatcher {
+    pub fn spawn(
+        config_path: PathBuf,
+        gateway_config: &GatewayConfigToml,
+        server_map: Arc<ServerMap>,
+    ) -> Self {
+        let check_duration =
+            Duration::from_millis(gateway_config.config_check_duration.unwrap_or(5000));
+
+        let task_handle = tokio::spawn(background_config_watch(
+            config_path,
+            server_map,
+            check_duration,
+        ));
+
+        Self { task_handle }
+    }
+}
+
+impl Drop for ConfigWatcher {
+    fn drop(&mut self) {
+        self.task_handle.abort();
+    }
+}
+
+async fn background_config_watch(
+    config_path: PathBuf,
+    server_map: Arc<ServerMap>,
+    check_duration: Duration,
+) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(e) => Some(e),
+        Err(err) => {
+            error!(
+                "failed to listen for SIGHUP: {err}, config reloads will only happen on file change"
+            );
+            None
+        }
+    };
+
+    let mut last_modified = modified_time(&config_path);
+
+    loop {
+        tokio::select! {
+            _ = sleep(check_duration) => {
+                let modified = modified_time(&config_path);
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+                info!("config file {config_path:?} changed, reloading");
+            }
+            Some(_) = async { sighup.as_mut()?.recv().await } => {
+                last_modified = modified_time(&config_path);
+                info!("SIGHUP received, reloading config file {config_path:?}");
+            }
+        }
+
+        if let Err(err) = reload_config(&config_path, &server_map).await {
+            error!("config reload rejected, keeping the old config => {err}");
+            continue;
+        }
+        info!("config reloaded from {config_path:?}");
+    }
+}
+
+async fn reload_config(config_path: &PathBuf, server_map: &ServerMap) -> Result<(), String> {
+    let config_toml = read_toml_file::<ConfigToml>(config_path).map_err(|e| e.to_string())?;
+
+    server_map
+        .reload_from_config_toml(config_toml)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+fn modified_time(config_path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(config_path).and_then(|e| e.modified()).ok()
+}
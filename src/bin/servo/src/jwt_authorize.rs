@@ -94,3 +94,14 @@ pub enum AuthError {
     #[error("jwt expired")]
     JWTExpired,
 }
+
+impl AuthError {
+    // used as the metrics label
+    pub fn reason(&self) -> &'static str {
+        match self {
+            AuthError::InvalidAuthHeader => "invalid_auth_header",
+            AuthError::InvalidJWT => "invalid_jwt",
+            AuthError::JWTExpired => "jwt_expired",
+        }
+    }
+}
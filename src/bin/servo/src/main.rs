@@ -1,14 +1,22 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc};
 
 use clap::Parser;
 use env_logger::Env;
-use log::{info, warn};
-use openssl::ssl::{SslAlert, SslRef};
-use pingora::{listeners::tls::TlsSettings, proxy::http_proxy_service, server::Server};
+use log::info;
+use pingora::{
+    apps::http_app::HttpServer, proxy::http_proxy_service, server::Server,
+    services::listening::Service,
+};
 use servo_toml::read_or_create_toml;
 use tokio::runtime::Runtime;
 
-use crate::{proxy::Proxy, tls::CertificateConfig};
+use crate::{
+    admin::AdminApp,
+    certificate_watcher::CertificateWatcher,
+    config_watcher::ConfigWatcher,
+    metrics::{BackendHealthCollector, CertificateExpiryCollector},
+    proxy::Proxy,
+};
 
 mod proxy;
 
@@ -23,12 +31,20 @@ use server_map::ServerMap;
 mod config_toml;
 pub use config_toml::ConfigToml;
 
+mod config_watcher;
+
+mod certificate_watcher;
+
 pub mod public_pem;
 
 pub mod tls;
 
 pub mod redis_cache;
 
+pub mod admin;
+
+pub mod metrics;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -51,37 +67,63 @@ fn main() -> Result<(), std::io::Error> {
     my_server.bootstrap();
 
     let rt = Runtime::new().unwrap();
-    let server_map = rt.block_on(ServerMap::build_from_config_toml(&config_toml));
-    let mut proxy = http_proxy_service(&my_server.configuration, Proxy { server_map });
+    let server_map = Arc::new(rt.block_on(ServerMap::build_from_config_toml(&config_toml)));
+    let _config_watcher = rt.block_on(async {
+        ConfigWatcher::spawn(args.config.clone(), &config_toml.config, server_map.clone())
+    });
+
+    if let Some(ref admin_toml) = config_toml.config.admin {
+        let admin_app = AdminApp::new(server_map.clone(), admin_toml);
+        let mut admin = Service::new("admin api".into(), HttpServer::new_app(admin_app));
+        admin.add_tcp(&admin_toml.listen.to_string());
+        info!("Admin api binded on: {}", admin_toml.listen);
+        my_server.add_service(admin);
+    }
+
+    let mut proxy = http_proxy_service(
+        &my_server.configuration,
+        Proxy {
+            server_map: server_map.clone(),
+        },
+    );
 
     for addr in &config_toml.config.listens {
         proxy.add_tcp(&addr.to_string());
         info!("Server binded on: {addr}")
     }
 
-    if let Some(ref e) = config_toml.config.tls {
-        let mut certificate_configs: Vec<tls::CertificateConfig> = Vec::new();
-        for tls_toml in e {
-            certificate_configs.push(CertificateConfig {
-                cert_path: tls_toml.cert_path.to_str().unwrap().to_owned(),
-                key_path: tls_toml.key_path.to_str().unwrap().to_owned(),
+    let mut certificates = Vec::new();
+    for tls_listen in config_toml.config.tls_listeners() {
+        let (tls_settings, listen_certificates) =
+            tls::tls_settings(&tls_listen).unwrap_or_else(|err| {
+                panic!(
+                    "unable to set up the tls listener {}: {err}",
+                    tls_listen.listen
+                )
             });
-        }
-        let certificates = tls::Certificates::new(&certificate_configs);
-        let mut tls_settings = TlsSettings::intermediate(
-            &certificates.default_cert_path,
-            &certificates.default_key_path,
+        proxy.add_tls_with_settings(&tls_listen.listen.to_string(), None, tls_settings);
+        info!("Tls server binded on: {}", tls_listen.listen);
+        certificates.push((tls_listen.listen, listen_certificates));
+    }
+    let _certificate_watcher = rt.block_on(async {
+        CertificateWatcher::spawn(
+            &config_toml.config,
+            certificates.iter().map(|e| e.1.clone()).collect(),
         )
-        .expect("unable to load or parse cert/key");
-        tls_settings.set_servername_callback(
-            move |ssl_ref: &mut SslRef, ssl_alert: &mut SslAlert| {
-                certificates.server_name_callback(ssl_ref, ssl_alert)
-            },
-        );
-        tls_settings.enable_h2();
-        tls_settings.set_alpn_select_callback(tls::prefer_h2);
-        warn!("binding on 0.0.0.0:443, bc tls is supplied");
-        proxy.add_tls_with_settings("0.0.0.0:443", None, tls_settings);
+    });
+
+    if let Some(ref metrics_toml) = config_toml.config.metrics {
+        prometheus::register(Box::new(BackendHealthCollector::new(server_map.clone())))
+            .unwrap_or_else(|err| panic!("failed to register backend health metrics: {err}"));
+        prometheus::register(Box::new(CertificateExpiryCollector::new(
+            certificates,
+            config_toml.config.cert_expiry_warning(),
+        )))
+        .unwrap_or_else(|err| panic!("failed to register certificate expiry metrics: {err}"));
+        let mut metrics = Service::prometheus_http_service();
+        metrics.add_tcp(&metrics_toml.listen.to_string());
+        info!("Metrics binded on: {}", metrics_toml.listen);
+        my_server.add_service(metrics);
     }
 
     my_server.add_service(proxy);
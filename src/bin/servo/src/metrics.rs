@@ -0,0 +1,297 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use prometheus::{
+    HistogramVec, IntCounterVec, IntGaugeVec, Opts,
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    register_histogram_vec, register_int_counter_vec,
+};
+
+use crate::{server_map::ServerMap, tls::Certificates};
+
+// the location label is the endpoint pattern the request matched,
+// requests that never matched a server / endpoint are labeled "none".
+// the variant label is "default" for locations without a split
+
+pub static REQUESTS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_requests_total",
+        "requests handled per server, location and response status class",
+        &["server", "location", "status_class"]
+    )
+    .unwrap()
+});
+
+pub static VARIANT_REQUESTS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_variant_requests_total",
+        "requests of split locations per variant and response status class",
+        &["server", "location", "variant", "status_class"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_LATENCY_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "servo_upstream_latency_seconds",
+        "time from picking a backend to receiving its response header",
+        &["server", "location"]
+    )
+    .unwrap()
+});
+
+pub static RATE_LIMITED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_rate_limited_total",
+        "requests rejected by the location rate limiter",
+        &["server", "location"]
+    )
+    .unwrap()
+});
+
+pub static JWT_FAILURES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_jwt_failures_total",
+        "requests rejected by jwt authorization",
+        &["server", "location", "reason"]
+    )
+    .unwrap()
+});
+
+pub static CIRCUIT_BREAKER_REJECTED_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_circuit_breaker_rejected_total",
+        "requests failed fast by an open location circuit breaker",
+        &["server", "location"]
+    )
+    .unwrap()
+});
+
+pub static BACKEND_UNAVAILABLE_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_backend_unavailable_total",
+        "requests failed fast because no backend of the location could take them",
+        &["server", "location", "reason"]
+    )
+    .unwrap()
+});
+
+pub static UPSTREAM_RETRIES_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_upstream_retries_total",
+        "requests retried on another backend by failure reason",
+        &["server", "location", "reason"]
+    )
+    .unwrap()
+});
+
+pub static MIRROR_REQUESTS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_mirror_requests_total",
+        "requests copied to shadow backends by result (sent, failed, skipped)",
+        &["server", "location", "result"]
+    )
+    .unwrap()
+});
+
+pub static CACHE_LOOKUPS_TOTAL: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "servo_cache_lookups_total",
+        "redis cache lookups by result (hit, miss, stale)",
+        &["result"]
+    )
+    .unwrap()
+});
+
+pub fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        500..=599 => "5xx",
+        _ => "none",
+    }
+}
+
+// reports the health of every backend and location circuit breaker in the
+// live server map on each scrape, so servers added / removed at runtime
+// never leave stale series behind
+pub struct BackendHealthCollector {
+    server_map: Arc<ServerMap>,
+    descs: Vec<Desc>,
+}
+
+impl BackendHealthCollector {
+    pub fn new(server_map: Arc<ServerMap>) -> Self {
+        let descs = BackendHealthGauges::new()
+            .collectors()
+            .iter()
+            .flat_map(|e| e.desc())
+            .cloned()
+            .collect();
+        Self { server_map, descs }
+    }
+}
+
+impl Collector for BackendHealthCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let gauges = BackendHealthGauges::new();
+        for server in self.server_map.servers.iter() {
+            for (endpoint, upstream) in &server.upstreams {
+                for (variant, proxy_pass) in upstream.proxy_passes() {
+                    for backend in proxy_pass.backends_health() {
+                        let labels = [server.name.as_str(), endpoint, variant, &backend.addr];
+                        gauges
+                            .healthy
+                            .with_label_values(&labels)
+                            .set(backend.healthy as i64);
+                        gauges
+                            .ejected
+                            .with_label_values(&labels)
+                            .set(backend.ejected as i64);
+                    }
+                    if let Some(circuit_breaker) = &proxy_pass.circuit_breaker {
+                        gauges
+                            .circuit_breaker_open
+                            .with_label_values(&[&server.name, endpoint, variant])
+                            .set(circuit_breaker.is_open() as i64);
+                    }
+                }
+            }
+        }
+        gauges
+            .collectors()
+            .iter()
+            .flat_map(|e| e.collect())
+            .collect()
+    }
+}
+
+struct BackendHealthGauges {
+    healthy: IntGaugeVec,
+    ejected: IntGaugeVec,
+    circuit_breaker_open: IntGaugeVec,
+}
+
+impl BackendHealthGauges {
+    fn new() -> Self {
+        let gauge = |name: &str, help: &str, labels: &[&str]| {
+            IntGaugeVec::new(Opts::new(name, help), labels).unwrap()
+        };
+        Self {
+            healthy: gauge(
+                "servo_backend_healthy",
+                "1 when the load balancer considers the backend healthy",
+                &["server", "location", "variant", "backend"],
+            ),
+            ejected: gauge(
+                "servo_backend_ejected",
+                "1 while the backend is ejected by outlier detection",
+                &["server", "location", "variant", "backend"],
+            ),
+            circuit_breaker_open: gauge(
+                "servo_circuit_breaker_open",
+                "1 while the location circuit breaker is open or half open",
+                &["server", "location", "variant"],
+            ),
+        }
+    }
+
+    fn collectors(&self) -> [&dyn Collector; 3] {
+        [&self.healthy, &self.ejected, &self.circuit_breaker_open]
+    }
+}
+
+// reports how long every loaded tls certificate has left on each scrape,
+// so a reloaded certificate shows up without restarting
+pub(crate) struct CertificateExpiryCollector {
+    certificates: Vec<(SocketAddr, Arc<Certificates>)>,
+    expiry_warning: Duration,
+    descs: Vec<Desc>,
+}
+
+impl CertificateExpiryCollector {
+    pub(crate) fn new(
+        certificates: Vec<(SocketAddr, Arc<Certificates>)>,
+        expiry_warning: Duration,
+    ) -> Self {
+        let descs = CertificateExpiryGauges::new()
+            .collectors()
+            .iter()
+            .flat_map(|e| e.desc())
+            .cloned()
+            .collect();
+        Self {
+            certificates,
+            expiry_warning,
+            descs,
+        }
+    }
+}
+
+impl Collector for CertificateExpiryCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        self.descs.iter().collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let gauges = CertificateExpiryGauges::new();
+        let now = chrono::Utc::now().timestamp();
+        for (listen, certificates) in &self.certificates {
+            let listen = listen.to_string();
+            for (cert_path, not_after) in certificates.expiries() {
+                let labels = [listen.as_str(), &cert_path];
+                let secs_left = not_after - now;
+                gauges
+                    .expiry_seconds
+                    .with_label_values(&labels)
+                    .set(secs_left);
+                gauges
+                    .expiring
+                    .with_label_values(&labels)
+                    .set((secs_left < self.expiry_warning.as_secs() as i64) as i64);
+            }
+        }
+        gauges
+            .collectors()
+            .iter()
+            .flat_map(|e| e.collect())
+            .collect()
+    }
+}
+
+struct CertificateExpiryGauges {
+    expiry_seconds: IntGaugeVec,
+    expiring: IntGaugeVec,
+}
+
+impl CertificateExpiryGauges {
+    fn new() -> Self {
+        let gauge = |name: &str, help: &str| {
+            IntGaugeVec::new(Opts::new(name, help), &["listen", "cert_path"]).unwrap()
+        };
+        Self {
+            expiry_seconds: gauge(
+                "servo_tls_certificate_expiry_seconds",
+                "seconds until the certificate notAfter, negative once expired",
+            ),
+            expiring: gauge(
+                "servo_tls_certificate_expiring",
+                "1 when the certificate is within cert_expiry_warning_days of its notAfter",
+            ),
+        }
+    }
+
+    fn collectors(&self) -> [&dyn Collector; 2] {
+        [&self.expiry_seconds, &self.expiring]
+    }
+}
@@ -1,29 +1,37 @@
+use crate::config_toml::HashKeyToml;
 use crate::jwt_authorize;
+use crate::metrics::{
+    BACKEND_UNAVAILABLE_TOTAL, CIRCUIT_BREAKER_REJECTED_TOTAL, JWT_FAILURES_TOTAL,
+    MIRROR_REQUESTS_TOTAL, RATE_LIMITED_TOTAL, REQUESTS_TOTAL, UPSTREAM_LATENCY_SECONDS,
+    UPSTREAM_RETRIES_TOTAL, VARIANT_REQUESTS_TOTAL, status_class,
+};
 use crate::proxy_ctx::{AfterFilterCTX, ProxyCTX};
-use crate::redis_cache::RedisCache;
-use crate::server_map::{DownStreamHost, ServerMap};
+use crate::server_map::{
+    ClientCert, Compression, Cors, DownStreamHost, HeaderRules, RetryableFailure, ServedFile,
+    ServerMap, TemplateValues, cookie,
+};
 use async_trait::async_trait;
 use bytes::Bytes;
-use fred::prelude::KeysInterface;
 use http::Uri;
 use log::{debug, error, info, warn};
-use pingora::ErrorType::HTTPStatus;
-use pingora::cache::cache_control::CacheControl;
-use pingora::cache::filters::resp_cacheable;
-use pingora::cache::{CacheKey, CacheMeta, CacheMetaDefaults, NoCacheReason, RespCacheable};
+use pingora::ErrorType::{self, HTTPStatus};
+use pingora::cache::{CacheKey, CacheMeta, NoCacheReason, RespCacheable};
 use pingora::http::{RequestHeader, ResponseHeader};
-use pingora::{Error, Result};
+use pingora::protocols::http::ServerSession;
+use pingora::proxy::FailToProxy;
+use pingora::{Error, ErrorSource, Result};
 use pingora::{
     prelude::HttpPeer,
     proxy::{ProxyHttp, Session},
 };
-use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
-use std::hash::{DefaultHasher, Hash, Hasher};
-use std::time::{Duration, SystemTime};
+use std::hash::{DefaultHasher, Hasher};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use uuid::Uuid;
 
 pub struct Proxy {
-    pub server_map: ServerMap,
+    pub server_map: Arc<ServerMap>,
 }
 
 #[async_trait]
@@ -36,14 +44,27 @@ impl ProxyHttp for Proxy {
 
     // gets the reqheader and chooses a server relating to it
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.request_id = session
+            .req_header()
+            .headers
+            .get("x-request-id")
+            .and_then(|e| e.to_str().ok())
+            .map(|e| e.to_owned())
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+
         let downstream_ip = match session.client_addr().map(|e| e.as_inet()) {
             Some(Some(e)) => e.ip(),
             _ => {
                 debug!("there is no ip/valid ip in header");
-                return Ok(true);
+                return Err(Error::explain(
+                    HTTPStatus(400),
+                    "Bad Request: no client address",
+                ));
             }
         };
 
+        let is_tls = is_tls(session);
+        let client_cert = client_cert(session);
         let req_header = session.req_header_mut();
 
         let is_websocket = req_header
@@ -69,25 +90,87 @@ impl ProxyHttp for Proxy {
             Ok(e) => e,
             Err(err) => {
                 debug!("unable to parse DownStreamHost => {err}");
-                return Ok(true);
+                return Err(Error::explain(
+                    HTTPStatus(400),
+                    "Bad Request: missing or invalid host header",
+                ));
             }
         };
 
-        let server = match self.server_map.routes.get(&host_header) {
+        let server = match self.server_map.route(&host_header) {
             Some(e) => e,
             None => {
                 debug!("unable to map the host header to a actual server!");
-                return Ok(true);
+                return Err(Error::explain(
+                    HTTPStatus(421),
+                    "Misdirected Request: unknown host",
+                ));
             }
         };
+        ctx.server = Some(server.clone());
+
+        if !is_tls
+            && let Some(https_redirect) = &server.https_redirect
+            && !https_redirect.is_exempt(endpoint)
+        {
+            debug!("redirecting {host_header} to https");
+            let resp = https_redirect.response(req_header, &host_header)?;
+            write_response(session, ctx, resp, Bytes::new()).await?;
+            return Ok(true);
+        }
+
+        if server.maintenance.blocks(&downstream_ip) {
+            debug!("server {} is in maintenance", server.name);
+            ctx.retry_after = server.maintenance.retry_after;
+            let Some(page) = &server.maintenance.page else {
+                return Err(Error::explain(
+                    HTTPStatus(503),
+                    "Service Unavailable: down for maintenance",
+                ));
+            };
+            let (mut resp, body) = page.response()?;
+            if let Some(retry_after) = ctx.retry_after {
+                let secs = retry_after.as_secs().max(1);
+                resp.insert_header(http::header::RETRY_AFTER, secs.to_string())?;
+            }
+            write_response(session, ctx, resp, body).await?;
+            return Ok(true);
+        }
 
         let route_match = match server.routes.at(endpoint) {
             Ok(e) => e,
             Err(err) => {
                 debug!("endpoint / path doesnt map to a upstream / proxy pass: {err}");
-                return Ok(true);
+                return Err(Error::explain(HTTPStatus(404), "Not Found"));
             }
         };
+        // a preflight is matched as the request it asks about
+        let is_preflight = Cors::is_preflight(req_header);
+        let preflight_header = match is_preflight {
+            true => preflight_request(req_header),
+            false => None,
+        };
+        let match_header = preflight_header.as_ref().unwrap_or(req_header);
+        let upstream = route_match
+            .value
+            .iter()
+            .find(|e| e.matcher.as_ref().is_none_or(|e| e.matches(match_header)));
+        let upstream = match upstream {
+            Some(e) => e.clone(),
+            None => {
+                debug!("no location sharing the endpoint matches the request");
+                return Err(Error::explain(HTTPStatus(404), "Not Found"));
+            }
+        };
+        ctx.cors = upstream.cors.clone().or_else(|| server.cors.clone());
+
+        // preflights are answered before rate limiting and auth, browsers
+        // never send credentials with them
+        if is_preflight && let Some(cors) = &ctx.cors {
+            let resp = cors.preflight_response(req_header)?;
+            write_response(session, ctx, resp, Bytes::new()).await?;
+            return Ok(true);
+        }
         let path_params = {
             let mut params = HashMap::new();
             for (key, value) in route_match.params.iter() {
@@ -95,18 +178,43 @@ impl ProxyHttp for Proxy {
             }
             params
         };
-        let upstream = route_match.value.clone();
 
         if let Some(rate_limiter) = &upstream.rate_limiter
             && rate_limiter.rate_limit(&downstream_ip)
         {
             debug!("request blocked bc ip: {downstream_ip} is ratelimited");
-            return Ok(true);
+            RATE_LIMITED_TOTAL
+                .with_label_values(&[&server.name, &upstream.endpoint])
+                .inc();
+            ctx.retry_after = Some(rate_limiter.retry_after());
+            return Err(Error::explain(HTTPStatus(429), "Too Many Requests"));
         }
 
         if upstream.blacklisted_endpoints.contains(endpoint) {
             debug!("request blocked bc endpoint is in the blacklist!");
-            return Ok(true);
+            return Err(Error::explain(HTTPStatus(403), "Forbidden"));
+        }
+
+        if let Some(client_cert_rule) = &upstream.client_cert {
+            let Some(client_cert) = &client_cert else {
+                debug!("request blocked bc there is no verified client certificate");
+                return Err(Error::explain(
+                    HTTPStatus(403),
+                    "Forbidden: client certificate required",
+                ));
+            };
+            if !client_cert_rule.allows(client_cert) {
+                debug!("request blocked bc the client certificate is not allowed");
+                return Err(Error::explain(
+                    HTTPStatus(403),
+                    "Forbidden: client certificate not allowed",
+                ));
+            }
+        }
+        if let Some(client_cert) = &client_cert
+            && let Err(e) = client_cert.insert_headers(req_header)
+        {
+            warn!("Failed to insert client certificate headers: {e}");
         }
 
         let jwt = if let Some(upstream_auth) = &upstream.auth
@@ -114,6 +222,9 @@ impl ProxyHttp for Proxy {
         {
             let jwt = jwt_authorize(req_header, upstream_auth).map_err(|err| {
                 info!("jwt error: {err}");
+                JWT_FAILURES_TOTAL
+                    .with_label_values(&[&server.name, &upstream.endpoint, err.reason()])
+                    .inc();
                 Error::explain(HTTPStatus(401), "Unauthorized")
             })?;
 
@@ -131,6 +242,9 @@ impl ProxyHttp for Proxy {
                         .unwrap_or(false);
 
                     if !has_access {
+                        JWT_FAILURES_TOTAL
+                            .with_label_values(&[&server.name, &upstream.endpoint, "missing_role"])
+                            .inc();
                         return Err(Error::explain(
                             HTTPStatus(403),
                             "Forbidden: Missing required roles",
@@ -157,25 +271,103 @@ impl ProxyHttp for Proxy {
             None
         };
 
-        let after_filter_ctx = AfterFilterCTX {
-            server: server.clone(),
+        let mut after_filter_ctx = AfterFilterCTX {
+            server,
             host_header,
             upstream,
             jwt,
             path_params,
+            variant: None,
         };
 
+        if let Some(traffic_split) = after_filter_ctx.upstream.traffic_split.clone() {
+            let key = load_balancing_key(session, &after_filter_ctx, &traffic_split.sticky_key);
+            let override_variant = session
+                .req_header()
+                .headers
+                .get(&traffic_split.override_header)
+                .and_then(|e| e.to_str().ok());
+            after_filter_ctx.variant = traffic_split.pick(&key, override_variant);
+        }
+
+        let proxy_pass = after_filter_ctx.proxy_pass();
+        let circuit_breaker = proxy_pass.circuit_breaker.clone();
+        ctx.deadline = proxy_pass
+            .timeouts
+            .total
+            .map(|total| Instant::now() + total);
         ctx.after_filter = Some(after_filter_ctx);
 
+        // locations without backends answer once the request passed auth
+        let after_filter_ctx = ctx.after_filter.as_ref().unwrap();
+        let upstream = after_filter_ctx.upstream.clone();
+        if let Some(static_response) = &upstream.static_response {
+            let (resp, body) = static_response.response()?;
+            write_response(session, ctx, resp, body).await?;
+            return Ok(true);
+        }
+        if let Some(redirect) = &upstream.redirect {
+            let resp = redirect.response(
+                &after_filter_ctx.path_params,
+                session.req_header().uri.query(),
+            )?;
+            write_response(session, ctx, resp, Bytes::new()).await?;
+            return Ok(true);
+        }
+        if let Some(static_files) = &upstream.static_files {
+            let served_file = static_files
+                .serve(session.req_header(), &after_filter_ctx.path_params)
+                .await?;
+            write_file(session, ctx, served_file).await?;
+            return Ok(true);
+        }
+
+        if let Some(circuit_breaker) = circuit_breaker
+            && !circuit_breaker.allow()
+        {
+            debug!("request blocked bc the location circuit breaker is open");
+            let after_filter_ctx = ctx.after_filter.as_ref().unwrap();
+            CIRCUIT_BREAKER_REJECTED_TOTAL
+                .with_label_values(&[
+                    &after_filter_ctx.server.name,
+                    &after_filter_ctx.upstream.endpoint,
+                ])
+                .inc();
+            return Err(Error::explain(
+                HTTPStatus(503),
+                "Service Unavailable: circuit breaker open",
+            ));
+        }
+
         if !is_websocket {
             session.enable_retry_buffering();
 
             let body = session.read_request_body().await?;
-            if let Some(data) = body {
+            if let Some(data) = &body {
                 let mut hasher = DefaultHasher::new();
-                hasher.write(&data);
+                hasher.write(data);
                 ctx.body_hash = Some(hasher.finish());
             }
+
+            // only bodies that were read whole can be copied
+            let after_filter_ctx = ctx.after_filter.as_ref().unwrap();
+            if let Some(mirror) = &after_filter_ctx.upstream.mirror
+                && mirror.sample()
+            {
+                let body = body.unwrap_or_default();
+                if session.is_body_done() && body.len() <= mirror.max_body_bytes {
+                    ctx.mirror_body = Some(body);
+                } else {
+                    debug!("request body too large to mirror");
+                    MIRROR_REQUESTS_TOTAL
+                        .with_label_values(&[
+                            &after_filter_ctx.server.name,
+                            &after_filter_ctx.upstream.endpoint,
+                            "skipped",
+                        ])
+                        .inc();
+                }
+            }
         } else {
             debug!("WebSocket connection detected. Skipping body parsing & buffering.");
         }
@@ -183,26 +375,71 @@ impl ProxyHttp for Proxy {
         Ok(false)
     }
 
-    // extracts a good proxy pass from the load balancer
+    // extracts a good proxy pass from the load balancer, retries skip
+    // the backends that were already tried
     async fn upstream_peer(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
         let after_filter_ctx = ctx.after_filter.as_ref().unwrap();
+        let upstream = after_filter_ctx.upstream.clone();
+        let proxy_pass = upstream.variant_proxy_pass(after_filter_ctx.variant);
 
-        let proxy_pass = after_filter_ctx
-            .upstream
-            .proxy_pass
-            .load_balancer
-            .select(b"", 256)
-            .ok_or_else(|| {
-                error!("failed to select proxypass / backend / upstream");
-                Error::explain(HTTPStatus(500), "Server is unavailable")
-            })?;
+        let key = match &proxy_pass.hash_key {
+            Some(hash_key) => load_balancing_key(session, after_filter_ctx, hash_key),
+            None => Vec::new(),
+        };
 
-        let mut peer = HttpPeer::new(&proxy_pass, false, "".into());
-        peer.options.connection_timeout = Some(Duration::from_millis(100));
+        if ctx.attempts > 0
+            && let Some(retry_policy) = &proxy_pass.retry_policy
+        {
+            let backoff = retry_policy.backoff(ctx.attempts);
+            if !backoff.is_zero() {
+                tokio::time::sleep(backoff).await;
+            }
+        }
+
+        if ctx
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(Error::explain(
+                HTTPStatus(504),
+                "total request timeout exceeded",
+            ));
+        }
+
+        // every backend down, ejected or at its max_connections is an overload,
+        // not a gateway error, so the client is told when to come back
+        let Some((backend, connection)) = proxy_pass.select(&key, &ctx.tried_backends) else {
+            let (reason, retry_after) = proxy_pass.unavailable();
+            warn!("no backend can take the request => {reason}");
+            let after_filter_ctx = ctx.after_filter.as_ref().unwrap();
+            BACKEND_UNAVAILABLE_TOTAL
+                .with_label_values(&[
+                    &after_filter_ctx.server.name,
+                    &after_filter_ctx.upstream.endpoint,
+                    reason,
+                ])
+                .inc();
+            ctx.retry_after = Some(retry_after);
+            return Err(Error::explain(
+                HTTPStatus(503),
+                "Service Unavailable: no backend available",
+            ));
+        };
+
+        let mut peer = HttpPeer::new(&backend, false, "".into());
+        if let Some(upstream_tls) = proxy_pass.tls(&backend.addr) {
+            upstream_tls.apply(&mut peer);
+        }
+        proxy_pass.timeouts.apply(&mut peer, ctx.deadline);
+        ctx.attempts += 1;
+        ctx.tried_backends.push(backend.addr.clone());
+        ctx.upstream_start = Some(Instant::now());
+        ctx.upstream_connection = Some(connection);
+        ctx.upstream_status = None;
 
         Ok(Box::new(peer))
     }
@@ -211,10 +448,28 @@ impl ProxyHttp for Proxy {
     // checks if the token has exp and roles
     async fn upstream_request_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         request: &mut RequestHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
+        // the backend is asked for encodings servo can undo for any client, or
+        // for the one encoding the cached copy of this client is kept in
+        if let Some(compression) = compression(ctx) {
+            let accept_encoding = match compression.cache_per_encoding {
+                true => compression
+                    .negotiate(session.req_header())
+                    .map_or("identity", |e| e.as_str())
+                    .to_owned(),
+                false => compression.upstream_accept_encoding(),
+            };
+            request.insert_header(http::header::ACCEPT_ENCODING, accept_encoding)?;
+        }
+
+        let values = template_values(session, ctx);
+        for header_rules in header_rules(ctx) {
+            header_rules.request.apply_request(request, &values);
+        }
+
         let ctx_after_filter = ctx.after_filter.as_ref().unwrap();
         let upstream = &ctx_after_filter.upstream;
 
@@ -245,9 +500,240 @@ impl ProxyHttp for Proxy {
         debug!("Dynamic reroute resolved to: {uri}");
 
         request.set_uri(uri);
+
+        // the shadow copy gets the request as the first backend sees it
+        if let Some(mirror) = &upstream.mirror
+            && let Some(body) = ctx.mirror_body.take()
+        {
+            let labels = [
+                ctx_after_filter.server.name.clone(),
+                upstream.endpoint.clone(),
+            ];
+            mirror.send(request.clone(), body, labels);
+        }
         Ok(())
     }
 
+    async fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        let status = upstream_response.status.as_u16();
+        ctx.upstream_status = Some(status);
+        if let Some(after_filter_ctx) = &ctx.after_filter
+            && let Some(upstream_start) = ctx.upstream_start
+        {
+            UPSTREAM_LATENCY_SECONDS
+                .with_label_values(&[
+                    &after_filter_ctx.server.name,
+                    &after_filter_ctx.upstream.endpoint,
+                ])
+                .observe(upstream_start.elapsed().as_secs_f64());
+        }
+
+        // nothing was sent downstream yet, so the response can still be
+        // dropped and the request sent to another backend
+        if let Some(after_filter_ctx) = &ctx.after_filter
+            && let Some(retry_policy) = &after_filter_ctx.proxy_pass().retry_policy
+            && ctx.upstream_connection.is_some()
+            && !session.as_ref().retry_buffer_truncated()
+            && retry_policy.should_retry(
+                RetryableFailure::Status(status),
+                &session.req_header().method,
+                ctx.attempts,
+            )
+        {
+            return Err(Error::explain(
+                HTTPStatus(status),
+                "retrying the request on another backend",
+            ));
+        }
+
+        // the upstream body is swapped for the page in response_body_filter
+        if status >= 500
+            && let Some(server) = &ctx.server
+            && let Some(error_page) = server.error_pages.render(status, status_reason(status))
+        {
+            upstream_response
+                .insert_header(http::header::CONTENT_TYPE, &error_page.content_type)?;
+            upstream_response.set_content_length(error_page.body.len())?;
+            upstream_response.remove_header(&http::header::TRANSFER_ENCODING);
+            upstream_response.remove_header(&http::header::CONTENT_ENCODING);
+            ctx.error_page = Some(error_page);
+        }
+
+        if let Some(compression) = compression(ctx)
+            && compression.cache_per_encoding
+            && ctx.error_page.is_none()
+        {
+            ctx.response_encoder =
+                compression.response_encoder(session.req_header(), upstream_response);
+        }
+        Ok(())
+    }
+
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        if let Some(error_page) = &ctx.error_page {
+            *body = end_of_stream.then(|| error_page.body.clone());
+        }
+        if compression(ctx).is_some_and(|e| !e.cache_per_encoding)
+            && let Some(response_encoder) = &mut ctx.response_encoder
+        {
+            response_encoder.encode(body, end_of_stream)?;
+        }
+        Ok(None)
+    }
+
+    // runs for cached responses too, unlike upstream_response_filter
+    async fn response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        if let Some(compression) = compression(ctx)
+            && !compression.cache_per_encoding
+        {
+            ctx.response_encoder =
+                compression.response_encoder(session.req_header(), upstream_response);
+        }
+
+        let values = template_values(session, ctx);
+        for header_rules in header_rules(ctx) {
+            header_rules
+                .response
+                .apply_response(upstream_response, &values);
+        }
+        if let Some(cors) = &ctx.cors {
+            cors.apply_response(session.req_header(), upstream_response)?;
+        }
+        apply_hsts(session, ctx, upstream_response)?;
+        Ok(())
+    }
+
+    // a response that is already streaming can only be cut off
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>> {
+        if ctx
+            .deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(Error::explain(
+                HTTPStatus(504),
+                "total request timeout exceeded",
+            ));
+        }
+        // encoded before the cache stores it
+        if compression(ctx).is_some_and(|e| e.cache_per_encoding)
+            && let Some(response_encoder) = &mut ctx.response_encoder
+        {
+            response_encoder.encode(body, end_of_stream)?;
+        }
+        Ok(None)
+    }
+
+    fn fail_to_connect(
+        &self,
+        session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<Error>,
+    ) -> Box<Error> {
+        // a retry picks a new backend, so the failed one is reported right away
+        if let Some(after_filter_ctx) = &ctx.after_filter {
+            let proxy_pass = after_filter_ctx.proxy_pass();
+            if let Some(connection) = ctx.upstream_connection.take() {
+                proxy_pass.report_backend(connection.addr(), false);
+            }
+
+            if let Some(retry_policy) = &proxy_pass.retry_policy
+                && retry_policy.should_retry(
+                    RetryableFailure::ConnectError,
+                    &session.req_header().method,
+                    ctx.attempts,
+                )
+            {
+                count_retry(after_filter_ctx, "connect_error");
+                e.set_retry(true);
+            }
+        }
+        e
+    }
+
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<Error> {
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        let retry_buffer_truncated = session.as_ref().retry_buffer_truncated();
+        e.retry
+            .decide_reuse(client_reused && !retry_buffer_truncated);
+
+        let Some(after_filter_ctx) = &ctx.after_filter else {
+            return e;
+        };
+        let proxy_pass = after_filter_ctx.proxy_pass();
+        let Some(retry_policy) = &proxy_pass.retry_policy else {
+            return e;
+        };
+
+        let (failure, reason) = match e.etype() {
+            ErrorType::ReadTimedout | ErrorType::WriteTimedout => {
+                (RetryableFailure::Timeout, "timeout")
+            }
+            HTTPStatus(status) => (RetryableFailure::Status(*status), "status"),
+            _ => return e,
+        };
+        if !retry_buffer_truncated
+            && retry_policy.should_retry(failure, &session.req_header().method, ctx.attempts)
+        {
+            if let Some(connection) = ctx.upstream_connection.take() {
+                proxy_pass.report_backend(connection.addr(), false);
+            }
+            count_retry(after_filter_ctx, reason);
+            e.set_retry(true);
+        }
+        e
+    }
+
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        let code = error_status(e);
+        if code > 0 {
+            respond_error(session, ctx, code, e)
+                .await
+                .unwrap_or_else(|err| {
+                    error!("failed to send error response to downstream: {err}");
+                });
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     fn request_cache_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<()> {
         let ctx_after_filter = ctx.after_filter.as_ref().unwrap();
         if let Some(upstream_cache) = &ctx_after_filter.upstream.cache {
@@ -289,8 +775,15 @@ impl ProxyHttp for Proxy {
             None => "no_body".into(),
         };
 
-        let key = format!("{host}:{path_and_query}:{jwt_body}:{body_hash}");
-        dbg!(&key);
+        let mut key = format!("{host}:{path_and_query}:{jwt_body}:{body_hash}");
+        if let Some(compression) = &ctx_after_filter.upstream.compression
+            && compression.cache_per_encoding
+        {
+            let encoding = compression.negotiate(req_header);
+            key.push(':');
+            key.push_str(encoding.map_or("identity", |e| e.as_str()));
+        }
+        debug!("cache key {key}");
         Ok(CacheKey::new(String::new(), key, String::new()))
     }
 
@@ -336,6 +829,41 @@ impl ProxyHttp for Proxy {
             warn!("{err}");
         }
 
+        // requests that reached a backend feed the outlier detection and
+        // circuit breaker, a missing upstream status means the attempt errored
+        if let Some(after_filter_ctx) = &ctx.after_filter
+            && ctx.upstream_start.is_some()
+        {
+            let success = ctx.upstream_status.is_some_and(|status| status < 500);
+            let proxy_pass = after_filter_ctx.proxy_pass();
+            if let Some(connection) = ctx.upstream_connection.take() {
+                proxy_pass.report_backend(connection.addr(), success);
+            }
+            if let Some(circuit_breaker) = &proxy_pass.circuit_breaker {
+                circuit_breaker.report(success);
+            }
+        }
+
+        let (server_name, location) = match &ctx.after_filter {
+            Some(e) => (e.server.name.as_str(), e.upstream.endpoint.as_str()),
+            None => ("none", "none"),
+        };
+        REQUESTS_TOTAL
+            .with_label_values(&[server_name, location, status_class(response_code)])
+            .inc();
+        if let Some(after_filter_ctx) = &ctx.after_filter
+            && after_filter_ctx.upstream.traffic_split.is_some()
+        {
+            VARIANT_REQUESTS_TOTAL
+                .with_label_values(&[
+                    server_name,
+                    location,
+                    after_filter_ctx.variant_name(),
+                    status_class(response_code),
+                ])
+                .inc();
+        }
+
         info!(
             "{} response code: {response_code}, addr: {}",
             self.request_summary(session, ctx),
@@ -344,6 +872,227 @@ impl ProxyHttp for Proxy {
     }
 }
 
+// the status pingora would answer with, except that upstream
+// timeouts are a 504 instead of a 502
+fn error_status(e: &Error) -> u16 {
+    if timeout_reason(e).is_some() {
+        return 504;
+    }
+    match (e.etype(), e.esource()) {
+        (HTTPStatus(code), _) => *code,
+        (_, ErrorSource::Upstream) => 502,
+        (
+            ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed,
+            ErrorSource::Downstream,
+        ) => 0,
+        (_, ErrorSource::Downstream) => 400,
+        (_, ErrorSource::Internal | ErrorSource::Unset) => 500,
+    }
+}
+
+// the server's error page when it has one for the status, otherwise an
+// empty body except for upstream timeouts which say what timed out
+async fn respond_error(session: &mut Session, ctx: &ProxyCTX, code: u16, e: &Error) -> Result<()> {
+    let mut resp = ServerSession::generate_error(code);
+    if let Some(retry_after) = ctx.retry_after {
+        let secs = retry_after.as_secs().max(1);
+        resp.insert_header(http::header::RETRY_AFTER, secs.to_string())?;
+    }
+    // lets the browser read errors like a 401 or 429
+    if let Some(cors) = &ctx.cors {
+        cors.apply_response(session.req_header(), &mut resp)?;
+    }
+    apply_hsts(session, ctx, &mut resp)?;
+
+    // only the messages servo wrote itself are shown, other errors
+    // can hold backend addresses
+    let message = match (timeout_reason(e), e.etype()) {
+        (Some(reason), _) => format!("Gateway Timeout: {reason}"),
+        (None, HTTPStatus(_)) => e.context.as_ref().map_or("", |e| e.as_str()).to_owned(),
+        (None, _) => status_reason(code).to_owned(),
+    };
+
+    let error_page = ctx
+        .server
+        .as_ref()
+        .and_then(|server| server.error_pages.render(code, &message));
+    let body = match error_page {
+        Some(error_page) => {
+            resp.insert_header(http::header::CONTENT_TYPE, &error_page.content_type)?;
+            error_page.body
+        }
+        None if timeout_reason(e).is_some() => Bytes::from(format!("{message}\n")),
+        None => Bytes::new(),
+    };
+    if !body.is_empty() {
+        resp.set_content_length(body.len())?;
+    }
+
+    session
+        .as_downstream_mut()
+        .write_error_response(resp, body)
+        .await
+}
+
+fn status_reason(status: u16) -> &'static str {
+    http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|e| e.canonical_reason())
+        .unwrap_or("Unknown")
+}
+
+fn timeout_reason(e: &Error) -> Option<&str> {
+    match (e.etype(), e.esource()) {
+        (HTTPStatus(504), _) => Some(e.context.as_ref().map_or("timeout", |e| e.as_str())),
+        (ErrorType::ConnectTimedout, ErrorSource::Upstream) => Some("upstream connect timed out"),
+        (ErrorType::TLSHandshakeTimedout, ErrorSource::Upstream) => {
+            Some("upstream tls handshake timed out")
+        }
+        (ErrorType::ReadTimedout, ErrorSource::Upstream) => Some("upstream read timed out"),
+        (ErrorType::WriteTimedout, ErrorSource::Upstream) => Some("upstream write timed out"),
+        _ => None,
+    }
+}
+
+// the preflight with the method it asks for, to pick the location
+fn preflight_request(req_header: &RequestHeader) -> Option<RequestHeader> {
+    let method = req_header
+        .headers
+        .get(http::header::ACCESS_CONTROL_REQUEST_METHOD)?
+        .to_str()
+        .ok()?;
+    let mut preflight_header = req_header.clone();
+    preflight_header.set_method(http::Method::from_bytes(method.as_bytes()).ok()?);
+    Some(preflight_header)
+}
+
+// answers written by servo itself get the same header rules and cors as
+// proxied responses
+async fn write_response(
+    session: &mut Session,
+    ctx: &ProxyCTX,
+    resp: ResponseHeader,
+    body: Bytes,
+) -> Result<()> {
+    let end_of_stream = body.is_empty() || session.req_header().method == http::Method::HEAD;
+    write_response_header(session, ctx, resp, end_of_stream).await?;
+    if !end_of_stream {
+        session.write_response_body(Some(body), true).await?;
+    }
+    Ok(())
+}
+
+// streams the file in chunks instead of reading it whole
+async fn write_file(session: &mut Session, ctx: &ProxyCTX, served_file: ServedFile) -> Result<()> {
+    let body = served_file
+        .body
+        .filter(|_| session.req_header().method != http::Method::HEAD);
+    let Some(mut body) = body else {
+        return write_response_header(session, ctx, served_file.resp, true).await;
+    };
+
+    write_response_header(session, ctx, served_file.resp, false).await?;
+    loop {
+        let chunk = body.read_chunk().await.map_err(|err| {
+            Error::because(ErrorType::ReadError, "failed to read a static file", err)
+        })?;
+        let end_of_stream = chunk.is_none() || body.is_done();
+        session.write_response_body(chunk, end_of_stream).await?;
+        if end_of_stream {
+            return Ok(());
+        }
+    }
+}
+
+async fn write_response_header(
+    session: &mut Session,
+    ctx: &ProxyCTX,
+    mut resp: ResponseHeader,
+    end_of_stream: bool,
+) -> Result<()> {
+    let values = template_values(session, ctx);
+    for header_rules in header_rules(ctx) {
+        header_rules.response.apply_response(&mut resp, &values);
+    }
+    // a preflight answer already carries its cors headers
+    if let Some(cors) = &ctx.cors
+        && !Cors::is_preflight(session.req_header())
+    {
+        cors.apply_response(session.req_header(), &mut resp)?;
+    }
+    apply_hsts(session, ctx, &mut resp)?;
+    session
+        .write_response_header(Box::new(resp), end_of_stream)
+        .await
+}
+
+fn is_tls(session: &Session) -> bool {
+    session.digest().is_some_and(|e| e.ssl_digest.is_some())
+}
+
+// the client certificate verified during the tls handshake
+fn client_cert(session: &Session) -> Option<ClientCert> {
+    session
+        .digest()
+        .and_then(|e| e.ssl_digest.as_ref())
+        .and_then(|e| e.extension.get::<ClientCert>())
+        .cloned()
+}
+
+// browsers ignore the header over plain http
+fn apply_hsts(session: &Session, ctx: &ProxyCTX, resp: &mut ResponseHeader) -> Result<()> {
+    if let Some(hsts) = ctx.server.as_ref().and_then(|e| e.hsts.as_ref())
+        && is_tls(session)
+    {
+        hsts.apply_response(resp)?;
+    }
+    Ok(())
+}
+
+fn compression(ctx: &ProxyCTX) -> Option<Arc<Compression>> {
+    ctx.after_filter
+        .as_ref()
+        .and_then(|e| e.upstream.compression.clone())
+}
+
+// the server's rules first so a location can override them
+fn header_rules(ctx: &ProxyCTX) -> impl Iterator<Item = &HeaderRules> {
+    let server_rules = ctx.server.as_ref().and_then(|e| e.header_rules.as_ref());
+    let location_rules = ctx
+        .after_filter
+        .as_ref()
+        .and_then(|e| e.upstream.header_rules.as_deref());
+    server_rules.into_iter().chain(location_rules)
+}
+
+fn template_values<'a>(session: &'a Session, ctx: &'a ProxyCTX) -> TemplateValues<'a> {
+    TemplateValues {
+        client_ip: session
+            .client_addr()
+            .and_then(|e| e.as_inet())
+            .map(|e| e.ip()),
+        request_id: &ctx.request_id,
+        request: session.req_header(),
+        path_params: ctx.after_filter.as_ref().map(|e| &e.path_params),
+        jwt_claims: ctx
+            .after_filter
+            .as_ref()
+            .and_then(|e| e.jwt.as_ref())
+            .map(|e| &e.serialized_body),
+    }
+}
+
+fn count_retry(after_filter_ctx: &AfterFilterCTX, reason: &str) {
+    debug!("retrying the request on another backend after a {reason} failure");
+    UPSTREAM_RETRIES_TOTAL
+        .with_label_values(&[
+            &after_filter_ctx.server.name,
+            &after_filter_ctx.upstream.endpoint,
+            reason,
+        ])
+        .inc();
+}
+
 fn concat_path(path: &str, suffix: &str) -> String {
     if path == suffix {
         return "/".to_string();
@@ -359,3 +1108,44 @@ fn concat_path(path: &str, suffix: &str) -> String {
         path.to_string()
     }
 }
+
+// the bytes a ketama location hashes, requests missing the configured
+// header / cookie / param / sub fall back to the client ip
+fn load_balancing_key(
+    session: &Session,
+    after_filter_ctx: &AfterFilterCTX,
+    hash_key: &HashKeyToml,
+) -> Vec<u8> {
+    let req_header = session.req_header();
+    let key = match hash_key {
+        HashKeyToml::ClientIp => None,
+        HashKeyToml::Header(name) => req_header
+            .headers
+            .get(name.as_str())
+            .map(|e| e.as_bytes().to_vec()),
+        HashKeyToml::Cookie(name) => cookie(req_header, name).map(|e| e.as_bytes().to_vec()),
+        HashKeyToml::PathParam(name) => after_filter_ctx
+            .path_params
+            .get(name)
+            .map(|e| e.as_bytes().to_vec()),
+        HashKeyToml::JwtSub => after_filter_ctx
+            .jwt
+            .as_ref()
+            .and_then(|e| e.serialized_body.get("sub"))
+            .map(|e| match e {
+                serde_json::Value::String(s) => s.as_bytes().to_vec(),
+                _ => e.to_string().into_bytes(),
+            }),
+    };
+
+    key.unwrap_or_else(|| {
+        if !matches!(hash_key, HashKeyToml::ClientIp) {
+            debug!("hash key {hash_key:?} not found in request, hashing the client ip");
+        }
+        session
+            .client_addr()
+            .and_then(|e| e.as_inet())
+            .map(|e| e.ip().to_string().into_bytes())
+            .unwrap_or_default()
+    })
+}
@@ -1,21 +1,69 @@
-use std::{collections::HashMap, hash::DefaultHasher, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use pingora::protocols::l4::socket::SocketAddr;
 
-use bytes::BytesMut;
 use servo_auth::jwt::{Jwt, algoritms::Rsa};
 
-use crate::server_map::{DownStreamHost, Server, Upstream};
+use crate::config_toml::DEFAULT_VARIANT;
+use crate::server_map::{
+    ConnectionGuard, Cors, DownStreamHost, ProxyPass, RenderedErrorPage, ResponseEncoder, Server,
+    Upstream,
+};
 
 #[derive(Debug)]
 pub struct ProxyCTX {
+    // the client's X-Request-Id or a generated one
+    pub request_id: String,
     pub after_filter: Option<AfterFilterCTX>,
+    // set as soon as the host is matched, so errors raised before the
+    // request is routed still get the server's error pages
+    pub server: Option<Arc<Server>>,
+    // the cors of the location or else the server, set once the request is routed
+    pub cors: Option<Arc<Cors>>,
+    // sent as Retry-After with the error response
+    pub retry_after: Option<Duration>,
+    // replaces the body of an upstream 5xx response
+    pub error_page: Option<RenderedErrorPage>,
+    // (de)compresses the response body, before the cache when the location
+    // keeps a copy per encoding and after it otherwise
+    pub response_encoder: Option<ResponseEncoder>,
+    // the request body of a request picked for mirroring, taken once the
+    // request to the first backend is built
+    pub mirror_body: Option<Bytes>,
     pub body_hash: Option<u64>,
+    pub upstream_start: Option<Instant>,
+    pub upstream_connection: Option<ConnectionGuard>,
+    pub upstream_status: Option<u16>,
+    // upstream attempts made so far and the backends they went to
+    pub attempts: usize,
+    pub tried_backends: Vec<SocketAddr>,
+    // set from the location total timeout once the request is routed
+    pub deadline: Option<Instant>,
 }
 
 impl ProxyCTX {
     pub fn new() -> Self {
         Self {
+            request_id: String::new(),
             after_filter: None,
+            server: None,
+            cors: None,
+            retry_after: None,
+            error_page: None,
+            response_encoder: None,
+            mirror_body: None,
             body_hash: None,
+            upstream_start: None,
+            upstream_connection: None,
+            upstream_status: None,
+            attempts: 0,
+            tried_backends: Vec::new(),
+            deadline: None,
         }
     }
 }
@@ -33,4 +81,19 @@ pub struct AfterFilterCTX {
     pub upstream: Arc<Upstream>,
     pub path_params: HashMap<String, String>,
     pub jwt: Option<Jwt<Rsa>>,
+    // the split variant the request was sent to, None is the default variant
+    pub variant: Option<usize>,
+}
+
+impl AfterFilterCTX {
+    pub fn proxy_pass(&self) -> &ProxyPass {
+        self.upstream.variant_proxy_pass(self.variant)
+    }
+
+    pub fn variant_name(&self) -> &str {
+        match (&self.upstream.traffic_split, self.variant) {
+            (Some(split), Some(index)) => &split.variants[index].name,
+            _ => DEFAULT_VARIANT,
+        }
+    }
 }
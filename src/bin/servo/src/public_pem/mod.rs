@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 mod public_pem;
 pub use public_pem::PublicPem;
 
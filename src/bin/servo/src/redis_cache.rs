@@ -1,5 +1,5 @@
 use std::any::Any;
-use std::time::Duration;
+use std::time::SystemTime;
 
 use async_trait::async_trait;
 use bytes::{Bytes, BytesMut};
@@ -13,6 +13,8 @@ use pingora::cache::{CacheMeta, HitHandler, MissHandler, PurgeType};
 use pingora::{Error, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::metrics::CACHE_LOOKUPS_TOTAL;
+
 #[derive(Debug, Clone)]
 pub struct RedisCache {
     redis_pool: Client,
@@ -133,6 +135,7 @@ impl Storage for RedisCache {
             }
             _ => {
                 log::info!("redis not found");
+                CACHE_LOOKUPS_TOTAL.with_label_values(&["miss"]).inc();
                 return Ok(None);
             }
         };
@@ -141,6 +144,13 @@ impl Storage for RedisCache {
 
         let meta = CacheMeta::deserialize(&obj.meta.0, &obj.meta.1)?;
 
+        let result = if meta.is_fresh(SystemTime::now()) {
+            "hit"
+        } else {
+            "stale"
+        };
+        CACHE_LOOKUPS_TOTAL.with_label_values(&[result]).inc();
+
         log::info!("redis calling hit handler");
         Ok(Some((meta, Box::new(RedisHitHandler::new(obj)))))
     }
@@ -169,8 +179,8 @@ impl Storage for RedisCache {
     async fn purge(
         &'static self,
         key: &CompactCacheKey,
-        purge_type: PurgeType,
-        trace: &SpanHandle,
+        _purge_type: PurgeType,
+        _trace: &SpanHandle,
     ) -> Result<bool> {
         log::info!("redis purge");
         let key = key.combined_bin();
@@ -181,7 +191,7 @@ impl Storage for RedisCache {
         &'static self,
         key: &CacheKey,
         meta: &CacheMeta,
-        trace: &SpanHandle,
+        _trace: &SpanHandle,
     ) -> Result<bool> {
         log::info!("redis update meta");
         let key = key.combined_bin();
@@ -203,7 +213,7 @@ impl Storage for RedisCache {
         };
 
         let new_obj: Vec<u8> = postcard::to_allocvec(&new_obj)
-            .map_err(|e| Error::new(Custom("EncodeRedisPostcardObj")))?;
+            .map_err(|_| Error::new(Custom("EncodeRedisPostcardObj")))?;
 
         let _: Option<()> = self
             .redis_pool
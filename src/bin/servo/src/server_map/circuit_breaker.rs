@@ -0,0 +1,126 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+
+use crate::config_toml::CircuitBreakerToml;
+
+// a per location breaker over the error ratio of fixed windows, while open
+// requests are failed fast without reaching any backend
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    error_ratio: f64,
+    min_requests: u64,
+    window: Duration,
+    open_time: Duration,
+    state: Mutex<CircuitState>,
+}
+
+#[derive(Debug)]
+enum CircuitState {
+    Closed {
+        window_start: Instant,
+        requests: u64,
+        failures: u64,
+    },
+    Open {
+        until: Instant,
+    },
+    // a single probe request decides if the circuit closes again, a probe
+    // that never reports back is replaced after open_time
+    HalfOpen {
+        probe_start: Instant,
+    },
+}
+
+impl CircuitState {
+    fn closed() -> Self {
+        CircuitState::Closed {
+            window_start: Instant::now(),
+            requests: 0,
+            failures: 0,
+        }
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(circuit_breaker_toml: &CircuitBreakerToml) -> Self {
+        Self {
+            error_ratio: circuit_breaker_toml.error_ratio,
+            min_requests: circuit_breaker_toml.min_requests.unwrap_or(20),
+            window: Duration::from_millis(circuit_breaker_toml.window_ms.unwrap_or(10_000)),
+            open_time: Duration::from_millis(circuit_breaker_toml.open_ms.unwrap_or(30_000)),
+            state: Mutex::new(CircuitState::closed()),
+        }
+    }
+
+    // false when the request should be failed fast
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match *state {
+            CircuitState::Closed { .. } => true,
+            CircuitState::Open { until } if now < until => false,
+            CircuitState::Open { .. } => {
+                info!("circuit breaker: half open, letting a probe request through");
+                *state = CircuitState::HalfOpen { probe_start: now };
+                true
+            }
+            CircuitState::HalfOpen { probe_start } if now - probe_start < self.open_time => false,
+            CircuitState::HalfOpen { .. } => {
+                *state = CircuitState::HalfOpen { probe_start: now };
+                true
+            }
+        }
+    }
+
+    pub fn report(&self, success: bool) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match &mut *state {
+            CircuitState::Closed {
+                window_start,
+                requests,
+                failures,
+            } => {
+                if now - *window_start >= self.window {
+                    *window_start = now;
+                    *requests = 0;
+                    *failures = 0;
+                }
+                *requests += 1;
+                if !success {
+                    *failures += 1;
+                }
+
+                let ratio = *failures as f64 / *requests as f64;
+                if *requests >= self.min_requests && ratio >= self.error_ratio {
+                    warn!(
+                        "circuit breaker: opening for {}ms, {failures} of {requests} requests failed",
+                        self.open_time.as_millis()
+                    );
+                    *state = CircuitState::Open {
+                        until: now + self.open_time,
+                    };
+                }
+            }
+            CircuitState::HalfOpen { .. } if success => {
+                info!("circuit breaker: probe succeeded, closing");
+                *state = CircuitState::closed();
+            }
+            CircuitState::HalfOpen { .. } => {
+                warn!("circuit breaker: probe failed, opening again");
+                *state = CircuitState::Open {
+                    until: now + self.open_time,
+                };
+            }
+            CircuitState::Open { .. } => {}
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !matches!(*self.state.lock().unwrap(), CircuitState::Closed { .. })
+    }
+}
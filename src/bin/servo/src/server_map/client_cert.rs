@@ -0,0 +1,200 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use openssl::{
+    hash::MessageDigest,
+    nid::Nid,
+    ssl::SslRef,
+    x509::{X509NameRef, X509Ref},
+};
+use pingora::http::RequestHeader;
+use thiserror::Error;
+
+use crate::config_toml::ClientCertToml;
+
+// the identity of a client certificate that passed verification, attached
+// to the tls connection once the handshake completes
+#[derive(Debug, Clone)]
+pub struct ClientCert {
+    // the full subject dn in rfc 4514 form, "CN=partner,OU=ops,O=Partner Inc"
+    pub subject: String,
+    pub common_name: Option<String>,
+    // dns names, emails, uris and ips
+    pub sans: Vec<String>,
+    // sha256 of the der certificate, lowercase hex
+    pub fingerprint: String,
+    pub serial: String,
+}
+
+impl ClientCert {
+    // None without a peer certificate or when it failed verification
+    pub fn from_ssl(ssl: &SslRef) -> Option<Self> {
+        let cert = ssl.peer_certificate()?;
+        if ssl.verify_result() != openssl::x509::X509VerifyResult::OK {
+            return None;
+        }
+        Self::from_x509(&cert)
+    }
+
+    fn from_x509(cert: &X509Ref) -> Option<Self> {
+        let subject = rfc4514_name(cert.subject_name());
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|e| e.data().as_utf8().ok())
+            .map(|e| e.to_string());
+
+        let mut sans = Vec::new();
+        for name in cert.subject_alt_names().iter().flatten() {
+            if let Some(dns) = name.dnsname() {
+                sans.push(dns.to_owned());
+            } else if let Some(email) = name.email() {
+                sans.push(email.to_owned());
+            } else if let Some(uri) = name.uri() {
+                sans.push(uri.to_owned());
+            } else if let Some(ip) = name.ipaddress().and_then(ip_from_bytes) {
+                sans.push(ip.to_string());
+            }
+        }
+
+        let fingerprint = cert
+            .digest(MessageDigest::sha256())
+            .ok()?
+            .iter()
+            .map(|e| format!("{e:02x}"))
+            .collect();
+        let serial = cert
+            .serial_number()
+            .to_bn()
+            .and_then(|e| e.to_hex_str().map(|e| e.to_lowercase()))
+            .ok()?;
+
+        Some(Self {
+            subject,
+            common_name,
+            sans,
+            fingerprint,
+            serial,
+        })
+    }
+
+    // forwarded like the jwt claims, clients cant send X-Gateway-* headers
+    // of their own since those are stripped first
+    pub fn insert_headers(&self, req_header: &mut RequestHeader) -> pingora::Result<()> {
+        req_header.insert_header("X-Gateway-Client-Cert-Subject", &self.subject)?;
+        if let Some(common_name) = &self.common_name {
+            req_header.insert_header("X-Gateway-Client-Cert-Cn", common_name)?;
+        }
+        if !self.sans.is_empty() {
+            req_header.insert_header("X-Gateway-Client-Cert-Sans", self.sans.join(","))?;
+        }
+        req_header.insert_header("X-Gateway-Client-Cert-Fingerprint", &self.fingerprint)?;
+        req_header.insert_header("X-Gateway-Client-Cert-Serial", &self.serial)?;
+        Ok(())
+    }
+}
+
+// a location that only takes clients with a verified certificate, the
+// certificate has to match one of the listed names or fingerprints when
+// there are any
+#[derive(Debug)]
+pub struct ClientCertRule {
+    common_names: HashSet<String>,
+    sans: HashSet<String>,
+    fingerprints: HashSet<String>,
+}
+
+impl ClientCertRule {
+    pub fn from_client_cert_toml(client_cert_toml: &ClientCertToml) -> Result<Self, Error> {
+        let mut fingerprints = HashSet::new();
+        for fingerprint in client_cert_toml.fingerprints.iter().flatten() {
+            let normalized = fingerprint.replace(':', "").to_lowercase();
+            if normalized.len() != 64 || !normalized.bytes().all(|e| e.is_ascii_hexdigit()) {
+                return Err(Error::InvalidFingerprint(fingerprint.clone()));
+            }
+            fingerprints.insert(normalized);
+        }
+
+        Ok(Self {
+            common_names: client_cert_toml
+                .common_names
+                .iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            sans: client_cert_toml.sans.iter().flatten().cloned().collect(),
+            fingerprints,
+        })
+    }
+
+    pub fn allows(&self, client_cert: &ClientCert) -> bool {
+        if self.common_names.is_empty() && self.sans.is_empty() && self.fingerprints.is_empty() {
+            return true;
+        }
+        client_cert
+            .common_name
+            .as_ref()
+            .is_some_and(|e| self.common_names.contains(e))
+            || client_cert.sans.iter().any(|e| self.sans.contains(e))
+            || self.fingerprints.contains(&client_cert.fingerprint)
+    }
+}
+
+// rfc 4514 lists the rdns last to first, so the CN usually leads
+fn rfc4514_name(name: &X509NameRef) -> String {
+    let mut rdns: Vec<String> = name
+        .entries()
+        .map(|entry| {
+            let object = entry.object();
+            let attribute = match object.nid().short_name() {
+                Ok(short_name) => short_name.to_owned(),
+                Err(_) => object.to_string(),
+            };
+            let value = entry
+                .data()
+                .as_utf8()
+                .map(|e| escape_rdn_value(&e))
+                .unwrap_or_default();
+            format!("{attribute}={value}")
+        })
+        .collect();
+    rdns.reverse();
+    rdns.join(",")
+}
+
+fn escape_rdn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        // control characters cant go in a header value, they are hex pairs
+        if c.is_control() {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                escaped.push_str(&format!("\\{byte:02x}"));
+            }
+            continue;
+        }
+        let needs_escape = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, ' ' | '#'))
+            || (i == last && c == ' ');
+        if needs_escape {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn ip_from_bytes(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => <[u8; 4]>::try_from(bytes).ok().map(IpAddr::from),
+        16 => <[u8; 16]>::try_from(bytes).ok().map(IpAddr::from),
+        _ => None,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid client cert fingerprint '{0}', expected a sha256 in hex")]
+    InvalidFingerprint(String),
+}
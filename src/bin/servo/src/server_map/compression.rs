@@ -0,0 +1,402 @@
+use std::{
+    fmt,
+    io::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use http::{HeaderValue, Method, header};
+use pingora::{
+    OrErr, Result,
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::compression::{Algorithm, COMPRESSION_ERROR, Encode},
+};
+use thiserror::Error;
+
+use crate::config_toml::CompressionToml;
+
+const DEFAULT_LEVEL: u32 = 6;
+const DEFAULT_MIN_SIZE_BYTES: usize = 1024;
+const DEFAULT_CONTENT_TYPES: &[&str] = &[
+    "text/",
+    "application/json",
+    "application/javascript",
+    "application/xml",
+    "image/svg+xml",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+    Zstd,
+}
+
+impl Encoding {
+    fn parse(encoding: &str) -> Option<Self> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    // the level is capped to what the algorithm supports
+    fn compressor(&self, level: u32) -> Option<Box<dyn Encode + Send + Sync>> {
+        match self {
+            Encoding::Gzip => Algorithm::Gzip.compressor(level.min(9)),
+            Encoding::Brotli => Algorithm::Brotli.compressor(level.min(11)),
+            Encoding::Zstd => Algorithm::Zstd.compressor(level.min(22)),
+        }
+    }
+
+    fn decompressor(&self) -> Option<Box<dyn Encode + Send + Sync>> {
+        match self {
+            Encoding::Gzip => Algorithm::Gzip.decompressor(true),
+            Encoding::Brotli => Algorithm::Brotli.decompressor(true),
+            Encoding::Zstd => Some(Box::new(ZstdDecompressor::new())),
+        }
+    }
+}
+
+// compresses responses for the clients that accept it and decompresses the
+// ones encoded in a way the client does not accept
+#[derive(Debug)]
+pub struct Compression {
+    algorithms: Vec<Encoding>,
+    level: u32,
+    min_size_bytes: usize,
+    content_types: Vec<String>,
+    // encode before the cache, keeping one cached copy per encoding
+    pub cache_per_encoding: bool,
+}
+
+impl Compression {
+    pub fn from_compression_toml(compression_toml: &CompressionToml) -> Result<Self, Error> {
+        if compression_toml.algorithms.is_empty() {
+            return Err(Error::NoAlgorithms);
+        }
+        let mut algorithms = Vec::new();
+        for algorithm in &compression_toml.algorithms {
+            let encoding = Encoding::parse(algorithm)
+                .ok_or_else(|| Error::UnknownAlgorithm(algorithm.into()))?;
+            if !algorithms.contains(&encoding) {
+                algorithms.push(encoding);
+            }
+        }
+
+        let level = compression_toml.level.unwrap_or(DEFAULT_LEVEL);
+        if level == 0 {
+            return Err(Error::InvalidLevel(level));
+        }
+
+        let content_types = match &compression_toml.content_types {
+            Some(content_types) => content_types
+                .iter()
+                .map(|e| e.trim().to_ascii_lowercase())
+                .collect(),
+            None => DEFAULT_CONTENT_TYPES
+                .iter()
+                .map(|e| e.to_string())
+                .collect(),
+        };
+
+        Ok(Self {
+            algorithms,
+            level,
+            min_size_bytes: compression_toml
+                .min_size_bytes
+                .unwrap_or(DEFAULT_MIN_SIZE_BYTES),
+            content_types,
+            cache_per_encoding: compression_toml.cache_per_encoding.unwrap_or(false),
+        })
+    }
+
+    // what the backend is asked for when one cached copy serves every client,
+    // any of these can be decoded again for clients that do not accept it
+    pub fn upstream_accept_encoding(&self) -> String {
+        let algorithms: Vec<&str> = self.algorithms.iter().map(|e| e.as_str()).collect();
+        algorithms.join(", ")
+    }
+
+    // the configured encoding the client prefers, None for identity
+    pub fn negotiate(&self, req_header: &RequestHeader) -> Option<Encoding> {
+        preferred_encoding(req_header, &self.algorithms)
+    }
+
+    // adjusts the response header and returns the encoder for its body, None
+    // when the body is passed through as is
+    pub fn response_encoder(
+        &self,
+        req_header: &RequestHeader,
+        resp: &mut ResponseHeader,
+    ) -> Option<ResponseEncoder> {
+        let status = resp.status.as_u16();
+        if req_header.method == Method::HEAD
+            || resp.status.is_informational()
+            || status == 204
+            || status == 304
+        {
+            return None;
+        }
+
+        let content_encoding = resp
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .map(|e| e.to_str().unwrap_or_default().trim().to_ascii_lowercase())
+            .filter(|e| !e.is_empty() && e != "identity");
+
+        let encoder = match content_encoding {
+            None => {
+                if !self.compressible(resp, true) {
+                    return None;
+                }
+                add_vary(resp);
+                let encoding = self.negotiate(req_header)?;
+                ResponseEncoder {
+                    decoder: None,
+                    encoder: Some(encoding.compressor(self.level)?),
+                    encoding: Some(encoding),
+                }
+            }
+            Some(content_encoding) => {
+                // unknown or stacked encodings are left untouched
+                let current = Encoding::parse(&content_encoding)?;
+                add_vary(resp);
+                if AcceptEncoding::parse(req_header).q(current) > 0.0 {
+                    return None;
+                }
+                // the content length is the encoded one, so only the type is checked
+                let encoding = self
+                    .compressible(resp, false)
+                    .then(|| self.negotiate(req_header))
+                    .flatten();
+                ResponseEncoder {
+                    decoder: Some(current.decompressor()?),
+                    encoder: encoding.and_then(|e| e.compressor(self.level)),
+                    encoding,
+                }
+            }
+        };
+
+        match encoder.encoding {
+            Some(encoding) => resp
+                .insert_header(header::CONTENT_ENCODING, encoding.as_str())
+                .ok()?,
+            None => {
+                resp.remove_header(&header::CONTENT_ENCODING);
+            }
+        }
+        // the body is streamed, so its length is no longer known
+        resp.remove_header(&header::CONTENT_LENGTH);
+        resp.remove_header(&header::ACCEPT_RANGES);
+        resp.insert_header(header::TRANSFER_ENCODING, "chunked")
+            .ok()?;
+        weaken_etag(resp);
+        Some(encoder)
+    }
+
+    fn compressible(&self, resp: &ResponseHeader, check_size: bool) -> bool {
+        let content_length = resp
+            .headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|e| e.to_str().ok())
+            .and_then(|e| e.parse::<usize>().ok());
+        if check_size && content_length.is_some_and(|e| e < self.min_size_bytes) {
+            return false;
+        }
+        let Some(content_type) = resp
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|e| e.to_str().ok())
+        else {
+            return false;
+        };
+        let content_type = content_type.trim().to_ascii_lowercase();
+        self.content_types
+            .iter()
+            .any(|e| content_type.starts_with(e.as_str()))
+    }
+}
+
+// decodes the body the backend sent and encodes it the way the client wants
+pub struct ResponseEncoder {
+    decoder: Option<Box<dyn Encode + Send + Sync>>,
+    encoder: Option<Box<dyn Encode + Send + Sync>>,
+    encoding: Option<Encoding>,
+}
+
+impl fmt::Debug for ResponseEncoder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseEncoder")
+            .field("decode", &self.decoder.is_some())
+            .field("encoding", &self.encoding)
+            .finish()
+    }
+}
+
+impl ResponseEncoder {
+    pub fn encode(&mut self, body: &mut Option<Bytes>, end_of_stream: bool) -> Result<()> {
+        let mut data = body.take().unwrap_or_default();
+        if let Some(decoder) = &mut self.decoder {
+            data = decoder.encode(&data, end_of_stream)?;
+        }
+        if let Some(encoder) = &mut self.encoder {
+            data = encoder.encode(&data, end_of_stream)?;
+        }
+        if !data.is_empty() {
+            *body = Some(data);
+        }
+        Ok(())
+    }
+}
+
+// the encoding out of the candidates the client prefers, ties go to the
+// earlier candidate
+pub fn preferred_encoding(req_header: &RequestHeader, candidates: &[Encoding]) -> Option<Encoding> {
+    let accepted = AcceptEncoding::parse(req_header);
+    let mut best: Option<(Encoding, f32)> = None;
+    for candidate in candidates {
+        let q = accepted.q(*candidate);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((*candidate, q));
+        }
+    }
+    best.map(|(e, _)| e)
+}
+
+struct AcceptEncoding {
+    codings: Vec<(String, f32)>,
+}
+
+impl AcceptEncoding {
+    fn parse(req_header: &RequestHeader) -> Self {
+        let mut codings = Vec::new();
+        for value in req_header.headers.get_all(header::ACCEPT_ENCODING) {
+            let Ok(value) = value.to_str() else {
+                continue;
+            };
+            for coding in value.split(',') {
+                let mut parts = coding.split(';');
+                let name = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                if name.is_empty() {
+                    continue;
+                }
+                let q = parts
+                    .filter_map(|e| e.trim().strip_prefix("q="))
+                    .find_map(|e| e.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                codings.push((name, q));
+            }
+        }
+        Self { codings }
+    }
+
+    // the q value for the encoding, 0 when it is not accepted
+    fn q(&self, encoding: Encoding) -> f32 {
+        let named = self
+            .codings
+            .iter()
+            .find(|(name, _)| Encoding::parse(name) == Some(encoding));
+        let any = self.codings.iter().find(|(name, _)| name == "*");
+        named.or(any).map_or(0.0, |(_, q)| *q)
+    }
+}
+
+fn add_vary(resp: &mut ResponseHeader) {
+    let already_varies = resp.headers.get_all(header::VARY).iter().any(|e| {
+        e.to_str().is_ok_and(|e| {
+            e.split(',')
+                .any(|e| e.trim() == "*" || e.trim().eq_ignore_ascii_case("accept-encoding"))
+        })
+    });
+    if !already_varies {
+        let _ = resp.append_header(header::VARY, "Accept-Encoding");
+    }
+}
+
+fn weaken_etag(resp: &mut ResponseHeader) {
+    let Some(etag) = resp.headers.get(header::ETAG) else {
+        return;
+    };
+    let etag = etag.as_bytes();
+    if etag.starts_with(b"W/") {
+        return;
+    }
+    match etag.starts_with(b"\"") {
+        true => {
+            let weak = HeaderValue::from_bytes(&[b"W/", etag].concat());
+            if let Ok(weak) = weak {
+                let _ = resp.insert_header(header::ETAG, weak);
+            }
+        }
+        false => {
+            resp.remove_header(&header::ETAG);
+        }
+    }
+}
+
+// pingora only ships zstd compression, not decompression
+struct ZstdDecompressor {
+    // Mutex because the decoder is not Sync
+    decompress: Mutex<::zstd::stream::write::Decoder<'static, Vec<u8>>>,
+    total_in: usize,
+    total_out: usize,
+    duration: Duration,
+}
+
+impl ZstdDecompressor {
+    fn new() -> Self {
+        Self {
+            decompress: Mutex::new(::zstd::stream::write::Decoder::new(Vec::new()).unwrap()),
+            total_in: 0,
+            total_out: 0,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+impl Encode for ZstdDecompressor {
+    fn encode(&mut self, input: &[u8], end: bool) -> Result<Bytes> {
+        let start = Instant::now();
+        self.total_in += input.len();
+        let mut decompress = self.decompress.lock().unwrap();
+        decompress
+            .write_all(input)
+            .or_err(COMPRESSION_ERROR, "while decompress zstd")?;
+        if end {
+            decompress
+                .flush()
+                .or_err(COMPRESSION_ERROR, "while decompress zstd")?;
+        }
+        self.total_out += decompress.get_ref().len();
+        self.duration += start.elapsed();
+        Ok(std::mem::take(decompress.get_mut()).into())
+    }
+
+    fn stat(&self) -> (&'static str, usize, usize, Duration) {
+        ("de-zstd", self.total_in, self.total_out, self.duration)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("compression needs at least one algorithm")]
+    NoAlgorithms,
+
+    #[error("Unknown compression algorithm '{0}', expected gzip, br or zstd")]
+    UnknownAlgorithm(String),
+
+    #[error("Invalid compression level {0}")]
+    InvalidLevel(u32),
+}
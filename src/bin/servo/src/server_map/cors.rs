@@ -0,0 +1,214 @@
+use http::{HeaderValue, Method, header};
+use pingora::{
+    Result,
+    http::{RequestHeader, ResponseHeader},
+};
+use regex::Regex;
+use thiserror::Error;
+
+use crate::config_toml::CorsToml;
+
+const DEFAULT_METHODS: &str = "GET, HEAD, POST";
+
+// answers preflights and adds the cors headers to responses, configured
+// per location or for the whole server
+#[derive(Debug)]
+pub struct Cors {
+    origins: Vec<OriginPattern>,
+    methods: String,
+    // None reflects the headers the preflight asks for
+    headers: Option<String>,
+    exposed_headers: Option<String>,
+    credentials: bool,
+    max_age_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+enum OriginPattern {
+    Any,
+    Exact(String),
+    // https://*.example.com, any subdomain
+    Wildcard { scheme: String, suffix: String },
+    Regex(Regex),
+}
+
+impl OriginPattern {
+    fn parse(pattern: &str) -> Result<Self, Error> {
+        if pattern == "*" {
+            return Ok(OriginPattern::Any);
+        }
+        if let Some(regex) = pattern.strip_prefix('~') {
+            let regex = Regex::new(regex)
+                .map_err(|err| Error::InvalidOrigin(pattern.into(), err.to_string()))?;
+            return Ok(OriginPattern::Regex(regex));
+        }
+        if let Some((scheme, host)) = pattern.split_once("://*")
+            && host.starts_with('.')
+        {
+            return Ok(OriginPattern::Wildcard {
+                scheme: format!("{}://", scheme.to_ascii_lowercase()),
+                suffix: host.to_ascii_lowercase(),
+            });
+        }
+        if pattern.contains('*') {
+            return Err(Error::InvalidOrigin(
+                pattern.into(),
+                "only a leading '*.' wildcard is supported".into(),
+            ));
+        }
+        Ok(OriginPattern::Exact(pattern.to_ascii_lowercase()))
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => origin.eq_ignore_ascii_case(exact),
+            OriginPattern::Wildcard { scheme, suffix } => {
+                let origin = origin.to_ascii_lowercase();
+                origin.strip_prefix(scheme.as_str()).is_some_and(|host| {
+                    host.len() > suffix.len() && host.ends_with(suffix.as_str())
+                })
+            }
+            OriginPattern::Regex(regex) => regex.is_match(origin),
+        }
+    }
+}
+
+impl Cors {
+    pub fn from_cors_toml(cors_toml: &CorsToml) -> Result<Self, Error> {
+        let origins = cors_toml
+            .allowed_origins
+            .iter()
+            .map(|e| OriginPattern::parse(e))
+            .collect::<Result<_, _>>()?;
+
+        let methods = match &cors_toml.allowed_methods {
+            Some(methods) => {
+                let mut parsed = Vec::new();
+                for method in methods {
+                    let method = Method::from_bytes(method.to_uppercase().as_bytes())
+                        .map_err(|_| Error::InvalidMethod(method.into()))?;
+                    parsed.push(method.to_string());
+                }
+                parsed.join(", ")
+            }
+            None => DEFAULT_METHODS.into(),
+        };
+
+        Ok(Self {
+            origins,
+            methods,
+            headers: cors_toml.allowed_headers.as_ref().map(|e| e.join(", ")),
+            exposed_headers: cors_toml.exposed_headers.as_ref().map(|e| e.join(", ")),
+            credentials: cors_toml.allow_credentials.unwrap_or(false),
+            max_age_secs: cors_toml.max_age_secs,
+        })
+    }
+
+    pub fn is_preflight(req_header: &RequestHeader) -> bool {
+        req_header.method == Method::OPTIONS
+            && req_header.headers.contains_key(header::ORIGIN)
+            && req_header
+                .headers
+                .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    }
+
+    // the Access-Control-Allow-Origin value for the request
+    fn allow_origin<'a>(&self, req_header: &'a RequestHeader) -> Option<&'a HeaderValue> {
+        let origin = req_header.headers.get(header::ORIGIN)?;
+        let origin_str = origin.to_str().ok()?;
+        self.origins
+            .iter()
+            .any(|e| e.matches(origin_str))
+            .then_some(origin)
+    }
+
+    fn any_origin(&self) -> bool {
+        // config validation rejects "*" together with credentials
+        self.origins.iter().any(|e| matches!(e, OriginPattern::Any))
+    }
+
+    // the answer to a preflight, a 403 when the origin is not allowed
+    pub fn preflight_response(&self, req_header: &RequestHeader) -> Result<ResponseHeader> {
+        let Some(origin) = self.allow_origin(req_header) else {
+            let mut resp = ResponseHeader::build(403, Some(3))?;
+            self.vary_origin(&mut resp)?;
+            resp.insert_header(header::CONTENT_LENGTH, 0)?;
+            return Ok(resp);
+        };
+
+        let mut resp = ResponseHeader::build(204, Some(8))?;
+        self.vary_origin(&mut resp)?;
+        self.insert_origin(&mut resp, origin)?;
+        resp.insert_header(header::ACCESS_CONTROL_ALLOW_METHODS, &self.methods)?;
+        let requested_headers = req_header
+            .headers
+            .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .and_then(|e| e.to_str().ok());
+        if let Some(headers) = self.headers.as_deref().or(requested_headers) {
+            resp.insert_header(header::ACCESS_CONTROL_ALLOW_HEADERS, headers)?;
+        }
+        if let Some(max_age_secs) = self.max_age_secs {
+            resp.insert_header(header::ACCESS_CONTROL_MAX_AGE, max_age_secs)?;
+        }
+        resp.insert_header(header::CONTENT_LENGTH, 0)?;
+        Ok(resp)
+    }
+
+    // the headers added to the response of an actual request
+    pub fn apply_response(
+        &self,
+        req_header: &RequestHeader,
+        resp: &mut ResponseHeader,
+    ) -> Result<()> {
+        self.vary_origin(resp)?;
+        let Some(origin) = self.allow_origin(req_header) else {
+            return Ok(());
+        };
+        self.insert_origin(resp, origin)?;
+        if let Some(exposed_headers) = &self.exposed_headers {
+            resp.insert_header(header::ACCESS_CONTROL_EXPOSE_HEADERS, exposed_headers)?;
+        }
+        Ok(())
+    }
+
+    // unless every origin gets "*", the answer depends on the Origin header
+    // whether it was allowed or not, so shared caches must key on it
+    fn vary_origin(&self, resp: &mut ResponseHeader) -> Result<()> {
+        if self.any_origin() {
+            return Ok(());
+        }
+        let varies = resp
+            .headers
+            .get_all(header::VARY)
+            .iter()
+            .filter_map(|e| e.to_str().ok())
+            .flat_map(|e| e.split(','))
+            .any(|e| e.trim() == "*" || e.trim().eq_ignore_ascii_case("origin"));
+        if !varies {
+            resp.append_header(header::VARY, "Origin")?;
+        }
+        Ok(())
+    }
+
+    fn insert_origin(&self, resp: &mut ResponseHeader, origin: &HeaderValue) -> Result<()> {
+        if self.any_origin() {
+            resp.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*")?;
+            return Ok(());
+        }
+        resp.insert_header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)?;
+        if self.credentials {
+            resp.insert_header(header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid cors origin '{0}' => {1}")]
+    InvalidOrigin(String, String),
+
+    #[error("Invalid cors method '{0}'")]
+    InvalidMethod(String),
+}
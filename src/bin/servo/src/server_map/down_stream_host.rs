@@ -18,26 +18,41 @@ impl Borrow<str> for DownStreamHost {
     }
 }
 
+// hosts are matched without their port and case insensitively
 impl From<&str> for DownStreamHost {
     fn from(host: &str) -> Self {
-        DownStreamHost(host.to_owned())
+        let host = match host.strip_prefix('[') {
+            // an ipv6 literal keeps its brackets
+            Some(rest) => rest
+                .split_once(']')
+                .map_or(host, |(ip, _)| &host[..ip.len() + 2]),
+            None => match host.rsplit_once(':') {
+                Some((name, port)) if port.bytes().all(|e| e.is_ascii_digit()) => name,
+                _ => host,
+            },
+        };
+        DownStreamHost(host.trim_end_matches('.').to_ascii_lowercase())
     }
 }
 
 impl From<String> for DownStreamHost {
     fn from(host: String) -> Self {
-        DownStreamHost(host)
+        DownStreamHost::from(host.as_str())
     }
 }
 
 impl TryFrom<&RequestHeader> for DownStreamHost {
     type Error = Error;
 
+    // http2 requests carry the host in the :authority of the uri
     fn try_from(req_header: &RequestHeader) -> Result<Self, Error> {
-        let host_header = req_header
-            .headers
-            .get("host")
-            .ok_or(Error::HostDoesNotExistInReqHeader())?;
+        let Some(host_header) = req_header.headers.get("host") else {
+            return req_header
+                .uri
+                .host()
+                .map(DownStreamHost::from)
+                .ok_or(Error::HostDoesNotExistInReqHeader());
+        };
 
         let host_header = host_header
             .to_str()
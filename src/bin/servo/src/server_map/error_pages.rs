@@ -0,0 +1,94 @@
+use std::{fs, ops::RangeInclusive};
+
+use bytes::Bytes;
+use http::StatusCode;
+use thiserror::Error;
+
+use crate::config_toml::ErrorPageToml;
+
+const DEFAULT_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+// the custom bodies a server answers its errors with, the first page
+// whose status range holds the status is used
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    pages: Vec<ErrorPage>,
+}
+
+#[derive(Debug)]
+struct ErrorPage {
+    statuses: RangeInclusive<u16>,
+    content_type: String,
+    template: String,
+}
+
+#[derive(Debug)]
+pub struct RenderedErrorPage {
+    pub content_type: String,
+    pub body: Bytes,
+}
+
+impl ErrorPages {
+    pub fn from_error_pages_toml(error_pages_toml: &[ErrorPageToml]) -> Result<Self, Error> {
+        let mut pages = Vec::new();
+        for error_page_toml in error_pages_toml {
+            let template = match (&error_page_toml.body, &error_page_toml.body_path) {
+                (Some(body), _) => body.clone(),
+                (None, Some(body_path)) => fs::read_to_string(body_path).map_err(|err| {
+                    Error::FailedToReadBody(body_path.display().to_string(), err.to_string())
+                })?,
+                (None, None) => String::new(),
+            };
+            pages.push(ErrorPage {
+                statuses: error_page_toml.status.min..=error_page_toml.status.max,
+                content_type: error_page_toml
+                    .content_type
+                    .clone()
+                    .unwrap_or(DEFAULT_CONTENT_TYPE.into()),
+                template,
+            });
+        }
+        Ok(Self { pages })
+    }
+
+    // fills {status}, {reason} and {message} in the page template,
+    // values are escaped for json and html pages
+    pub fn render(&self, status: u16, message: &str) -> Option<RenderedErrorPage> {
+        let page = self.pages.iter().find(|e| e.statuses.contains(&status))?;
+        let reason = StatusCode::from_u16(status)
+            .ok()
+            .and_then(|e| e.canonical_reason())
+            .unwrap_or("Unknown");
+
+        let escape = |value: &str| -> String {
+            if page.content_type.contains("json") {
+                let quoted = serde_json::Value::from(value).to_string();
+                quoted[1..quoted.len() - 1].to_owned()
+            } else if page.content_type.contains("html") {
+                value
+                    .replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+                    .replace('"', "&quot;")
+            } else {
+                value.to_owned()
+            }
+        };
+        let body = page
+            .template
+            .replace("{status}", &status.to_string())
+            .replace("{reason}", &escape(reason))
+            .replace("{message}", &escape(message));
+
+        Some(RenderedErrorPage {
+            content_type: page.content_type.clone(),
+            body: Bytes::from(body),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read error page '{0}' => {1}")]
+    FailedToReadBody(String, String),
+}
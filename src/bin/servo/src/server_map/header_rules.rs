@@ -0,0 +1,223 @@
+use std::{collections::HashMap, net::IpAddr};
+
+use http::{HeaderMap, HeaderName};
+use log::warn;
+use pingora::http::{RequestHeader, ResponseHeader};
+use thiserror::Error;
+
+use crate::config_toml::{HeaderActionsToml, HeaderRulesToml};
+
+// the add / set / remove rules of a server or location, for the request
+// sent upstream and for the response sent downstream
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    pub request: HeaderActions,
+    pub response: HeaderActions,
+}
+
+// applied in order: remove, then set, then add
+#[derive(Debug, Default)]
+pub struct HeaderActions {
+    remove: Vec<HeaderName>,
+    set: Vec<(HeaderName, HeaderTemplate)>,
+    add: Vec<(HeaderName, HeaderTemplate)>,
+}
+
+// a header value with {placeholders} filled in per request
+#[derive(Debug)]
+pub struct HeaderTemplate(Vec<TemplatePart>);
+
+#[derive(Debug)]
+enum TemplatePart {
+    Constant(String),
+    ClientIp,
+    RequestId,
+    Host,
+    Method,
+    Path,
+    PathParam(String),
+    JwtClaim(String),
+    Header(HeaderName),
+}
+
+// what the placeholders of a template are filled from
+pub struct TemplateValues<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub request_id: &'a str,
+    pub request: &'a RequestHeader,
+    pub path_params: Option<&'a HashMap<String, String>>,
+    pub jwt_claims: Option<&'a serde_json::Value>,
+}
+
+impl HeaderRules {
+    pub fn from_header_rules_toml(header_rules_toml: &HeaderRulesToml) -> Result<Self, Error> {
+        Ok(Self {
+            request: HeaderActions::from_header_actions_toml(header_rules_toml.request.as_ref())?,
+            response: HeaderActions::from_header_actions_toml(header_rules_toml.response.as_ref())?,
+        })
+    }
+}
+
+impl HeaderActions {
+    fn from_header_actions_toml(
+        header_actions_toml: Option<&HeaderActionsToml>,
+    ) -> Result<Self, Error> {
+        let Some(header_actions_toml) = header_actions_toml else {
+            return Ok(Self::default());
+        };
+
+        let header_name = |name: &str| {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| Error::InvalidName(name.into()))
+        };
+        let templates = |headers: &Option<HashMap<String, String>>| {
+            let mut templates = Vec::new();
+            for (name, value) in headers.iter().flatten() {
+                templates.push((header_name(name)?, HeaderTemplate::parse(value)?));
+            }
+            // sorted so the rules apply the same way on every load
+            templates.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+            Ok::<_, Error>(templates)
+        };
+
+        Ok(Self {
+            remove: header_actions_toml
+                .remove
+                .iter()
+                .flatten()
+                .map(|e| header_name(e))
+                .collect::<Result<_, _>>()?,
+            set: templates(&header_actions_toml.set)?,
+            add: templates(&header_actions_toml.add)?,
+        })
+    }
+
+    pub fn apply_request(&self, req_header: &mut RequestHeader, values: &TemplateValues) {
+        for name in &self.remove {
+            req_header.remove_header(name);
+        }
+        for (name, template) in &self.set {
+            let value = template.render(values);
+            if let Err(err) = req_header.insert_header(name.clone(), value) {
+                warn!("failed to set request header {name}: {err}");
+            }
+        }
+        for (name, template) in &self.add {
+            let value = template.render(values);
+            if let Err(err) = req_header.append_header(name.clone(), value) {
+                warn!("failed to add request header {name}: {err}");
+            }
+        }
+    }
+
+    pub fn apply_response(&self, resp_header: &mut ResponseHeader, values: &TemplateValues) {
+        for name in &self.remove {
+            resp_header.remove_header(name);
+        }
+        for (name, template) in &self.set {
+            let value = template.render(values);
+            if let Err(err) = resp_header.insert_header(name.clone(), value) {
+                warn!("failed to set response header {name}: {err}");
+            }
+        }
+        for (name, template) in &self.add {
+            let value = template.render(values);
+            if let Err(err) = resp_header.append_header(name.clone(), value) {
+                warn!("failed to add response header {name}: {err}");
+            }
+        }
+    }
+}
+
+impl HeaderTemplate {
+    // {client_ip}, {request_id}, {host}, {method}, {path}, {path.<param>},
+    // {jwt.<claim>} and {header.<name>}, everything else is a constant
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            if start > 0 {
+                parts.push(TemplatePart::Constant(rest[..start].to_owned()));
+            }
+            let placeholder = &rest[start + 1..start + len];
+            let part = match placeholder.split_once('.') {
+                None => match placeholder {
+                    "client_ip" => TemplatePart::ClientIp,
+                    "request_id" => TemplatePart::RequestId,
+                    "host" => TemplatePart::Host,
+                    "method" => TemplatePart::Method,
+                    "path" => TemplatePart::Path,
+                    _ => return Err(Error::UnknownPlaceholder(placeholder.into())),
+                },
+                Some(("path", name)) => TemplatePart::PathParam(name.to_owned()),
+                Some(("jwt", claim)) => TemplatePart::JwtClaim(claim.to_owned()),
+                Some(("header", name)) => TemplatePart::Header(
+                    HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|_| Error::InvalidName(name.into()))?,
+                ),
+                Some(_) => return Err(Error::UnknownPlaceholder(placeholder.into())),
+            };
+            parts.push(part);
+            rest = &rest[start + len + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Constant(rest.to_owned()));
+        }
+        Ok(Self(parts))
+    }
+
+    // placeholders without a value render empty
+    pub fn render(&self, values: &TemplateValues) -> String {
+        let mut rendered = String::new();
+        for part in &self.0 {
+            match part {
+                TemplatePart::Constant(constant) => rendered.push_str(constant),
+                TemplatePart::ClientIp => {
+                    if let Some(client_ip) = values.client_ip {
+                        rendered.push_str(&client_ip.to_string());
+                    }
+                }
+                TemplatePart::RequestId => rendered.push_str(values.request_id),
+                TemplatePart::Host => {
+                    rendered.push_str(header_value(&values.request.headers, &http::header::HOST))
+                }
+                TemplatePart::Method => rendered.push_str(values.request.method.as_str()),
+                TemplatePart::Path => rendered.push_str(values.request.uri.path()),
+                TemplatePart::PathParam(name) => {
+                    if let Some(value) = values.path_params.and_then(|e| e.get(name)) {
+                        rendered.push_str(value);
+                    }
+                }
+                TemplatePart::JwtClaim(claim) => {
+                    match values.jwt_claims.and_then(|e| e.get(claim)) {
+                        Some(serde_json::Value::String(value)) => rendered.push_str(value),
+                        Some(value) => rendered.push_str(&value.to_string()),
+                        None => {}
+                    }
+                }
+                TemplatePart::Header(name) => {
+                    rendered.push_str(header_value(&values.request.headers, name))
+                }
+            }
+        }
+        rendered
+    }
+}
+
+fn header_value<'a>(headers: &'a HeaderMap, name: &HeaderName) -> &'a str {
+    headers
+        .get(name)
+        .and_then(|e| e.to_str().ok())
+        .unwrap_or_default()
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid header name '{0}'")]
+    InvalidName(String),
+
+    #[error("Unknown header template placeholder '{{{0}}}'")]
+    UnknownPlaceholder(String),
+}
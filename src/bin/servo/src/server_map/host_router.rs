@@ -0,0 +1,107 @@
+use std::{borrow::Borrow, collections::HashMap, sync::Arc};
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::server_map::{DownStreamHost, Server};
+
+// maps a request host to its server, exact hosts win over wildcards, the
+// longest wildcard wins over shorter ones, regexes are tried in config
+// order and the default server takes whatever is left
+#[derive(Debug, Default)]
+pub struct HostRouter {
+    exact: HashMap<DownStreamHost, Arc<Server>>,
+    // the suffix after the '*', longest first
+    wildcards: Vec<(String, Arc<Server>)>,
+    regexes: Vec<(Regex, Arc<Server>)>,
+    default_server: Option<Arc<Server>>,
+}
+
+pub enum HostPattern {
+    Exact(DownStreamHost),
+    // *.example.com, matches every subdomain but not example.com itself
+    Wildcard(String),
+    // ~^api-[0-9]+\.example\.com$
+    Regex(Regex),
+}
+
+impl HostPattern {
+    pub fn parse(pattern: &str) -> Result<Self, Error> {
+        if let Some(regex) = pattern.strip_prefix('~') {
+            let regex = Regex::new(regex)
+                .map_err(|err| Error::InvalidRegex(pattern.into(), err.to_string()))?;
+            return Ok(HostPattern::Regex(regex));
+        }
+        if let Some(suffix) = pattern.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.contains('*') {
+                return Err(Error::InvalidWildcard(pattern.into()));
+            }
+            return Ok(HostPattern::Wildcard(suffix.to_ascii_lowercase()));
+        }
+        if pattern.contains('*') {
+            return Err(Error::InvalidWildcard(pattern.into()));
+        }
+        Ok(HostPattern::Exact(DownStreamHost::from(pattern)))
+    }
+}
+
+impl HostRouter {
+    pub fn new<'a>(servers: impl Iterator<Item = &'a Arc<Server>>) -> Result<Self, Error> {
+        let mut host_router = HostRouter::default();
+        for server in servers {
+            if server.default_server {
+                host_router.default_server = Some(server.clone());
+            }
+            for downstream_host in &server.downstream_hosts {
+                match HostPattern::parse(downstream_host)? {
+                    HostPattern::Exact(host) => {
+                        host_router.exact.insert(host, server.clone());
+                    }
+                    HostPattern::Wildcard(suffix) => {
+                        host_router.wildcards.push((suffix, server.clone()));
+                    }
+                    HostPattern::Regex(regex) => {
+                        host_router.regexes.push((regex, server.clone()));
+                    }
+                }
+            }
+        }
+        host_router
+            .wildcards
+            .sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Ok(host_router)
+    }
+
+    pub fn route(&self, host: &DownStreamHost) -> Option<Arc<Server>> {
+        if let Some(server) = self.exact.get(host) {
+            return Some(server.clone());
+        }
+
+        let host_str: &str = host.borrow();
+        let wildcard = self.wildcards.iter().find(|(suffix, _)| {
+            host_str.len() > suffix.len() && host_str.ends_with(suffix.as_str())
+        });
+        if let Some((_, server)) = wildcard {
+            return Some(server.clone());
+        }
+
+        if let Some((_, server)) = self
+            .regexes
+            .iter()
+            .find(|(regex, _)| regex.is_match(host_str))
+        {
+            return Some(server.clone());
+        }
+
+        self.default_server.clone()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid wildcard host '{0}', only a leading '*.' is supported")]
+    InvalidWildcard(String),
+
+    #[error("Invalid regex host '{0}' => {1}")]
+    InvalidRegex(String, String),
+}
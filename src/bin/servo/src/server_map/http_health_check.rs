@@ -0,0 +1,133 @@
+use std::{collections::HashMap, ops::RangeInclusive, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use pingora::{
+    Error, ErrorType, Result,
+    connectors::http::Connector,
+    http::RequestHeader,
+    lb::{Backend, health_check::HealthCheck},
+    prelude::HttpPeer,
+    protocols::l4::socket::SocketAddr,
+};
+
+use crate::{
+    config_toml::{HttpHealthCheckToml, StatusRangeToml},
+    server_map::UpstreamTls,
+};
+
+// bodies are only read up to this size when looking for body_contains
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+// pingora's http health check can only validate the response header,
+// this one also checks the status range and the response body
+pub struct HttpHealthCheck {
+    req: RequestHeader,
+    expected_status: RangeInclusive<u16>,
+    body_contains: Option<String>,
+    timeout: Duration,
+    rise: usize,
+    fall: usize,
+    peer_template: HttpPeer,
+    // backends proxied over tls are checked over tls too
+    backend_tls: Arc<HashMap<SocketAddr, UpstreamTls>>,
+    connector: Connector,
+}
+
+impl HttpHealthCheck {
+    pub fn new(
+        http_health_check_toml: &HttpHealthCheckToml,
+        backend_tls: Arc<HashMap<SocketAddr, UpstreamTls>>,
+    ) -> Result<Box<Self>> {
+        let method = http_health_check_toml.method.as_deref().unwrap_or("GET");
+        let path = http_health_check_toml.path.as_deref().unwrap_or("/");
+        let mut req = RequestHeader::build(method, path.as_bytes(), None)?;
+        if let Some(host) = &http_health_check_toml.host {
+            req.insert_header(http::header::HOST, host)?;
+        }
+
+        let StatusRangeToml { min, max } = http_health_check_toml
+            .expected_status
+            .unwrap_or(StatusRangeToml { min: 200, max: 299 });
+        let timeout = Duration::from_millis(http_health_check_toml.timeout_ms.unwrap_or(1000));
+
+        // the address is replaced by the backend address on every check
+        let mut peer_template = HttpPeer::new("0.0.0.0:1", false, String::new());
+        peer_template.options.connection_timeout = Some(timeout);
+        peer_template.options.read_timeout = Some(timeout);
+
+        Ok(Box::new(Self {
+            req,
+            expected_status: min..=max,
+            body_contains: http_health_check_toml.body_contains.clone(),
+            timeout,
+            rise: http_health_check_toml.rise.unwrap_or(1),
+            fall: http_health_check_toml.fall.unwrap_or(1),
+            peer_template,
+            backend_tls,
+            connector: Connector::new(None),
+        }))
+    }
+
+    async fn check_backend(&self, target: &Backend) -> Result<()> {
+        let mut peer = self.peer_template.clone();
+        peer._address = target.addr.clone();
+        if let Some(upstream_tls) = self.backend_tls.get(&target.addr) {
+            upstream_tls.apply(&mut peer);
+        }
+
+        let mut req = self.req.clone();
+        if req.headers.get(http::header::HOST).is_none() {
+            req.insert_header(http::header::HOST, target.addr.to_string())?;
+        }
+
+        let (mut session, _) = self.connector.get_http_session(&peer).await?;
+        session.write_request_header(Box::new(req)).await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+
+        let status = session
+            .response_header()
+            .expect("response header was just read")
+            .status
+            .as_u16();
+        if !self.expected_status.contains(&status) {
+            return Error::e_explain(
+                ErrorType::CustomCode("unexpected status", status),
+                "during http health check",
+            );
+        }
+
+        let Some(body_contains) = &self.body_contains else {
+            return Ok(());
+        };
+        let mut body = BytesMut::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_BODY_SIZE {
+                break;
+            }
+        }
+        if !String::from_utf8_lossy(&body).contains(body_contains.as_str()) {
+            return Error::e_explain(
+                ErrorType::Custom("unexpected body"),
+                "during http health check",
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl HealthCheck for HttpHealthCheck {
+    fn health_threshold(&self, success: bool) -> usize {
+        if success { self.rise } else { self.fall }
+    }
+
+    async fn check(&self, target: &Backend) -> Result<()> {
+        tokio::time::timeout(self.timeout, self.check_backend(target))
+            .await
+            .map_err(|_| Error::explain(ErrorType::ReadTimedout, "http health check timed out"))?
+    }
+}
@@ -0,0 +1,126 @@
+use pingora::http::{RequestHeader, ResponseHeader};
+use thiserror::Error;
+
+use crate::{
+    config_toml::{HstsToml, HttpsRedirectToml},
+    server_map::DownStreamHost,
+};
+
+// hsts preload lists only take a max-age of at least a year
+const PRELOAD_MIN_MAX_AGE_SECS: u64 = 31_536_000;
+
+// sends plain http requests to the same host, path and query over https
+#[derive(Debug)]
+pub struct HttpsRedirect {
+    status: u16,
+    // left out of the location when it is 443
+    https_port: Option<u16>,
+    exempt_paths: Vec<ExemptPath>,
+}
+
+// "/robots.txt" is matched exactly, "/.well-known/acme-challenge/*" by prefix
+#[derive(Debug)]
+enum ExemptPath {
+    Exact(String),
+    Prefix(String),
+}
+
+impl HttpsRedirect {
+    pub fn from_https_redirect_toml(
+        https_redirect_toml: &HttpsRedirectToml,
+    ) -> Result<Self, Error> {
+        let status = https_redirect_toml.status.unwrap_or(301);
+        if ![301, 302, 307, 308].contains(&status) {
+            return Err(Error::UnsupportedStatus(status));
+        }
+
+        let mut exempt_paths = Vec::new();
+        for path in https_redirect_toml.exempt_paths.iter().flatten() {
+            if !path.starts_with('/') {
+                return Err(Error::RelativeExemptPath(path.clone()));
+            }
+            exempt_paths.push(match path.strip_suffix('*') {
+                Some(prefix) => ExemptPath::Prefix(prefix.to_owned()),
+                None => ExemptPath::Exact(path.clone()),
+            });
+        }
+
+        Ok(Self {
+            status,
+            https_port: https_redirect_toml.https_port.filter(|e| *e != 443),
+            exempt_paths,
+        })
+    }
+
+    pub fn is_exempt(&self, path: &str) -> bool {
+        self.exempt_paths.iter().any(|e| match e {
+            ExemptPath::Exact(exact) => path == exact,
+            ExemptPath::Prefix(prefix) => path.starts_with(prefix.as_str()),
+        })
+    }
+
+    pub fn response(
+        &self,
+        req_header: &RequestHeader,
+        host: &DownStreamHost,
+    ) -> pingora::Result<ResponseHeader> {
+        let path_and_query = req_header.uri.path_and_query().map_or("/", |e| e.as_str());
+        let location = match self.https_port {
+            Some(port) => format!("https://{host}:{port}{path_and_query}"),
+            None => format!("https://{host}{path_and_query}"),
+        };
+
+        let mut resp = ResponseHeader::build(self.status, Some(2))?;
+        resp.insert_header(http::header::LOCATION, location)?;
+        resp.insert_header(http::header::CONTENT_LENGTH, "0")?;
+        Ok(resp)
+    }
+}
+
+// the Strict-Transport-Security header, only sent over tls
+#[derive(Debug)]
+pub struct Hsts {
+    value: String,
+}
+
+impl Hsts {
+    pub fn from_hsts_toml(hsts_toml: &HstsToml) -> Result<Self, Error> {
+        let include_subdomains = hsts_toml.include_subdomains.unwrap_or(false);
+        let preload = hsts_toml.preload.unwrap_or(false);
+        if preload && (!include_subdomains || hsts_toml.max_age_secs < PRELOAD_MIN_MAX_AGE_SECS) {
+            return Err(Error::PreloadNotAllowed);
+        }
+
+        let mut value = format!("max-age={}", hsts_toml.max_age_secs);
+        if include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if preload {
+            value.push_str("; preload");
+        }
+        Ok(Self { value })
+    }
+
+    // a backend's own header is kept
+    pub fn apply_response(&self, resp: &mut ResponseHeader) -> pingora::Result<()> {
+        if !resp
+            .headers
+            .contains_key(http::header::STRICT_TRANSPORT_SECURITY)
+        {
+            resp.insert_header(http::header::STRICT_TRANSPORT_SECURITY, &self.value)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("https redirect status must be 301, 302, 307 or 308, got {0}")]
+    UnsupportedStatus(u16),
+
+    #[error("exempt path '{0}' must start with /")]
+    RelativeExemptPath(String),
+
+    #[error("hsts preload needs include_subdomains and a max_age_secs of at least a year")]
+    PreloadNotAllowed,
+}
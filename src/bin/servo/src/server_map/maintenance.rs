@@ -0,0 +1,69 @@
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use crate::{
+    config_toml::MaintenanceToml,
+    server_map::{StaticResponse, static_response::Error},
+};
+
+// answers every request of a server with a 503 while enabled, the allowed
+// ips still reach the backends. toggled at runtime without rebuilding the
+// server, so health checks and rate limits carry on
+#[derive(Debug, Default)]
+pub struct Maintenance {
+    enabled: AtomicBool,
+    allowed_ips: HashSet<IpAddr>,
+    pub retry_after: Option<Duration>,
+    // the server's 503 error page is used when there is no page of its own
+    pub page: Option<StaticResponse>,
+}
+
+impl Maintenance {
+    pub fn from_maintenance_toml(
+        maintenance_toml: Option<&MaintenanceToml>,
+    ) -> Result<Self, Error> {
+        let Some(maintenance_toml) = maintenance_toml else {
+            return Ok(Self::default());
+        };
+
+        let page = match (&maintenance_toml.body, &maintenance_toml.body_path) {
+            (None, None) => None,
+            (body, body_path) => Some(StaticResponse::new(
+                503,
+                None,
+                maintenance_toml.content_type.as_deref(),
+                body.as_deref(),
+                body_path.as_deref(),
+            )?),
+        };
+
+        Ok(Self {
+            enabled: AtomicBool::new(maintenance_toml.enabled.unwrap_or(false)),
+            allowed_ips: maintenance_toml
+                .allowed_ips
+                .iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            retry_after: maintenance_toml.retry_after_secs.map(Duration::from_secs),
+            page,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    // true when the client gets the maintenance page instead of the backends
+    pub fn blocks(&self, client_ip: &IpAddr) -> bool {
+        self.is_enabled() && !self.allowed_ips.contains(client_ip)
+    }
+}
@@ -0,0 +1,135 @@
+use core::fmt;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use log::debug;
+use pingora::{Result, connectors::http::Connector, http::RequestHeader, prelude::HttpPeer};
+
+use crate::{
+    config_toml::{LocationToml, MirrorToml},
+    metrics::MIRROR_REQUESTS_TOTAL,
+    server_map::{ProxyPass, proxy_pass::Error as ProxyPassError},
+};
+
+const SHADOW_HEADER: &str = "x-gateway-shadow";
+
+// copies requests of a location to a shadow group of backends, the copies
+// run detached from the request so they never add client latency
+pub struct Mirror {
+    pub proxy_pass: ProxyPass,
+    pub percent: u64,
+    pub max_body_bytes: usize,
+    tag: bool,
+    timeout: Duration,
+    requests_seen: AtomicU64,
+    connector: Connector,
+}
+
+impl Mirror {
+    pub fn from_location_toml(
+        location_toml: &LocationToml,
+        mirror_toml: &MirrorToml,
+    ) -> Result<Self, ProxyPassError> {
+        // the shadow backends share every location setting but the backends
+        let mut mirror_location = location_toml.clone();
+        mirror_location.proxy_passes = mirror_toml.proxy_passes.clone();
+        Ok(Self {
+            proxy_pass: ProxyPass::try_from(&mirror_location)?,
+            percent: mirror_toml.percent.unwrap_or(100) as u64,
+            max_body_bytes: mirror_toml.max_body_bytes.unwrap_or(64 * 1024),
+            tag: mirror_toml.tag.unwrap_or(true),
+            timeout: Duration::from_millis(mirror_toml.timeout_ms.unwrap_or(5000)),
+            requests_seen: AtomicU64::new(0),
+            connector: Connector::new(None),
+        })
+    }
+
+    // spreads the sampled requests evenly, a request is copied whenever it
+    // pushes the copied count up to the next whole share
+    pub fn sample(&self) -> bool {
+        let seen = self.requests_seen.fetch_add(1, Ordering::Relaxed) % 100;
+        (seen + 1) * self.percent / 100 > seen * self.percent / 100
+    }
+
+    // sends the copy on its own task, the labels are for the mirror metric
+    pub fn send(self: &Arc<Self>, mut req_header: RequestHeader, body: Bytes, labels: [String; 2]) {
+        if self.tag
+            && let Err(err) = req_header.insert_header(SHADOW_HEADER, "1")
+        {
+            debug!("failed to tag the mirrored request: {err}");
+        }
+
+        let mirror = self.clone();
+        tokio::spawn(async move {
+            let result =
+                tokio::time::timeout(mirror.timeout, mirror.send_request(req_header, body)).await;
+            let result = match result {
+                Ok(Ok(())) => "sent",
+                Ok(Err(err)) => {
+                    debug!("mirrored request failed: {err}");
+                    "failed"
+                }
+                Err(_) => {
+                    debug!("mirrored request timed out");
+                    "failed"
+                }
+            };
+            MIRROR_REQUESTS_TOTAL
+                .with_label_values(&[&labels[0], &labels[1], result])
+                .inc();
+        });
+    }
+
+    async fn send_request(&self, req_header: RequestHeader, body: Bytes) -> Result<()> {
+        let Some((backend, connection)) = self.proxy_pass.select(&[], &[]) else {
+            return pingora::Error::e_explain(
+                pingora::ErrorType::ConnectNoRoute,
+                "no shadow backend available",
+            );
+        };
+        let mut peer = HttpPeer::new(&backend, false, "".into());
+        if let Some(upstream_tls) = self.proxy_pass.tls(&backend.addr) {
+            upstream_tls.apply(&mut peer);
+        }
+        self.proxy_pass.timeouts.apply(&mut peer, None);
+
+        let result = async {
+            let (mut session, _) = self.connector.get_http_session(&peer).await?;
+            session.write_request_header(Box::new(req_header)).await?;
+            if !body.is_empty() {
+                session.write_request_body(body, true).await?;
+            }
+            session.finish_request_body().await?;
+            session.read_response_header().await?;
+            // the response is only drained
+            while session.read_response_body().await?.is_some() {}
+            self.connector
+                .release_http_session(session, &peer, peer.options.idle_timeout)
+                .await;
+            Ok(())
+        }
+        .await;
+
+        self.proxy_pass
+            .report_backend(connection.addr(), result.is_ok());
+        result
+    }
+}
+
+impl fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mirror")
+            .field("proxy_pass", &self.proxy_pass)
+            .field("percent", &self.percent)
+            .field("max_body_bytes", &self.max_body_bytes)
+            .field("tag", &self.tag)
+            .field("timeout", &self.timeout)
+            .finish()
+    }
+}
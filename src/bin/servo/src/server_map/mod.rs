@@ -1,20 +1,78 @@
 mod down_stream_host;
 pub use down_stream_host::DownStreamHost;
 
+mod compression;
+pub use compression::{Compression, Encoding, ResponseEncoder, preferred_encoding};
+
+mod static_response;
+pub use static_response::{Redirect, StaticResponse};
+
+mod static_files;
+pub use static_files::{FileBody, ServedFile, StaticFiles};
+
+mod maintenance;
+pub use maintenance::Maintenance;
+
+mod client_cert;
+pub use client_cert::{ClientCert, ClientCertRule};
+
+mod https_redirect;
+pub use https_redirect::{Hsts, HttpsRedirect};
+
+mod cors;
+pub use cors::Cors;
+
+mod circuit_breaker;
+pub use circuit_breaker::CircuitBreaker;
+
+mod error_pages;
+pub use error_pages::{ErrorPages, RenderedErrorPage};
+
+mod header_rules;
+pub use header_rules::{HeaderRules, TemplateValues};
+
+mod host_router;
+pub use host_router::{HostPattern, HostRouter};
+
+mod http_health_check;
+pub use http_health_check::HttpHealthCheck;
+
 mod proxy_pass;
-pub use proxy_pass::ProxyPass;
+pub use proxy_pass::{BackendHealth, ConnectionGuard, ProxyPass};
 
-mod server;
+pub mod server;
 pub use server::Server;
 
+#[allow(clippy::module_inception)]
 pub mod server_map;
 pub use server_map::ServerMap;
 
+mod traffic_split;
+pub use traffic_split::{TrafficSplit, Variant};
+
 mod upstream;
 pub use upstream::Upstream;
 
+mod upstream_timeouts;
+pub use upstream_timeouts::UpstreamTimeouts;
+
+mod upstream_tls;
+pub use upstream_tls::UpstreamTls;
+
 mod upstream_auth;
 pub use upstream_auth::UpstreamAuth;
 
+mod mirror;
+pub use mirror::Mirror;
+
+mod outlier_detector;
+pub use outlier_detector::OutlierDetector;
+
 mod rate_limiter;
 pub use rate_limiter::RateLimiter;
+
+mod request_matcher;
+pub use request_matcher::{RequestMatcher, cookie};
+
+mod retry_policy;
+pub use retry_policy::{RetryPolicy, RetryableFailure};
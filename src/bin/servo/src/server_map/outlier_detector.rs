@@ -0,0 +1,117 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use pingora::protocols::l4::socket::SocketAddr;
+
+use crate::config_toml::OutlierDetectionToml;
+
+// passive health checking fed from real responses, a backend is ejected
+// from selection after consecutive failures and re-admitted once its
+// ejection window is over
+#[derive(Debug)]
+pub struct OutlierDetector {
+    consecutive_failures: usize,
+    base_ejection: Duration,
+    max_ejection: Duration,
+    backends: HashMap<SocketAddr, BackendOutlierState>,
+}
+
+#[derive(Debug, Default)]
+struct BackendOutlierState {
+    consecutive_failures: AtomicUsize,
+    ejection: Mutex<Ejection>,
+}
+
+#[derive(Debug, Default)]
+struct Ejection {
+    until: Option<Instant>,
+    // ejections in a row without a success in between, drives the backoff
+    times_ejected: u32,
+}
+
+impl OutlierDetector {
+    pub fn new<'a>(
+        outlier_detection_toml: &OutlierDetectionToml,
+        addrs: impl Iterator<Item = &'a SocketAddr>,
+    ) -> Self {
+        Self {
+            consecutive_failures: outlier_detection_toml.consecutive_failures.unwrap_or(5),
+            base_ejection: Duration::from_millis(
+                outlier_detection_toml.base_ejection_ms.unwrap_or(10_000),
+            ),
+            max_ejection: Duration::from_millis(
+                outlier_detection_toml.max_ejection_ms.unwrap_or(300_000),
+            ),
+            backends: addrs
+                .map(|addr| (addr.clone(), BackendOutlierState::default()))
+                .collect(),
+        }
+    }
+
+    pub fn is_ejected(&self, addr: &SocketAddr) -> bool {
+        let Some(state) = self.backends.get(addr) else {
+            return false;
+        };
+        let mut ejection = state.ejection.lock().unwrap();
+        match ejection.until {
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                info!("outlier detection: re-admitting backend {addr}");
+                ejection.until = None;
+                false
+            }
+            None => false,
+        }
+    }
+
+    // how long the backend stays ejected, None when it takes requests
+    pub fn ejection_remaining(&self, addr: &SocketAddr) -> Option<Duration> {
+        let until = self.backends.get(addr)?.ejection.lock().unwrap().until?;
+        until
+            .checked_duration_since(Instant::now())
+            .filter(|e| !e.is_zero())
+    }
+
+    pub fn report(&self, addr: &SocketAddr, success: bool) {
+        let Some(state) = self.backends.get(addr) else {
+            return;
+        };
+
+        if success {
+            state.consecutive_failures.store(0, Ordering::Relaxed);
+            let mut ejection = state.ejection.lock().unwrap();
+            if ejection.until.is_none() {
+                ejection.times_ejected = 0;
+            }
+            return;
+        }
+
+        let failures = state.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.consecutive_failures {
+            return;
+        }
+
+        let mut ejection = state.ejection.lock().unwrap();
+        if ejection.until.is_some() {
+            return;
+        }
+        let ejection_time = self
+            .base_ejection
+            .saturating_mul(2u32.saturating_pow(ejection.times_ejected))
+            .min(self.max_ejection);
+        ejection.until = Some(Instant::now() + ejection_time);
+        ejection.times_ejected = ejection.times_ejected.saturating_add(1);
+        state.consecutive_failures.store(0, Ordering::Relaxed);
+        warn!(
+            "outlier detection: ejecting backend {addr} for {}ms after {failures} consecutive failures",
+            ejection_time.as_millis()
+        );
+    }
+}
@@ -1,64 +1,510 @@
 use core::fmt;
+use futures::FutureExt;
 use pingora::{
-    lb::LoadBalancer,
-    prelude::{RoundRobin, TcpHealthCheck, background_service},
+    lb::{
+        Backend, Backends, Extensions, LoadBalancer,
+        discovery::Static,
+        selection::{BackendIter, BackendSelection, Consistent, Random},
+    },
+    prelude::{RoundRobin, TcpHealthCheck},
+    protocols::l4::socket::SocketAddr,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::ToSocketAddrs,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::Duration,
 };
-use std::{sync::Arc, time::Duration};
 use thiserror::Error;
+use tokio::{sync::watch, task::JoinHandle};
+
+use crate::{
+    config_toml::{HashKeyToml, LoadBalancingToml, LocationToml},
+    server_map::{
+        CircuitBreaker, HttpHealthCheck, OutlierDetector, RetryPolicy, UpstreamTimeouts,
+        UpstreamTls, upstream_tls::Error as UpstreamTlsError,
+    },
+};
 
-use crate::config_toml::LocationToml;
+// sent when every backend is down or full, the same answer as a rate limit
+const UNAVAILABLE_RETRY_AFTER: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct ProxyPass {
     pub addrs: Vec<String>,
-    pub load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    pub balancer: Balancer,
+    // the backup backends, only picked when no primary backend can take the request
+    pub backup_balancer: Option<Balancer>,
+    // only set for ketama, the other algorithms ignore the key
+    pub hash_key: Option<HashKeyToml>,
+    // per backend connection counts and limits, shared by every
+    // endpoint of the location
+    backend_states: Arc<HashMap<SocketAddr, BackendState>>,
+    least_connections_offset: Arc<AtomicUsize>,
+    pub outlier_detector: Option<Arc<OutlierDetector>>,
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    pub retry_policy: Option<Arc<RetryPolicy>>,
+    pub timeouts: UpstreamTimeouts,
+    // only backends proxied over tls have an entry
+    backend_tls: Arc<HashMap<SocketAddr, UpstreamTls>>,
+    #[allow(unused)]
+    health_check_tasks: Arc<Vec<HealthCheckTask>>,
+}
+
+#[derive(Clone)]
+pub enum Balancer {
+    RoundRobin(Arc<LoadBalancer<RoundRobin>>),
+    Random(Arc<LoadBalancer<Random>>),
+    // pingora has no least connections selector, the round robin one only
+    // holds the backends and their health and the pick is done by hand
+    LeastConnections(Arc<LoadBalancer<RoundRobin>>),
+    Ketama(Arc<LoadBalancer<Consistent>>),
+}
+
+#[derive(Debug)]
+struct BackendState {
+    active_connections: AtomicUsize,
+    max_connections: Option<usize>,
+}
+
+#[derive(Debug)]
+pub struct BackendHealth {
+    pub addr: String,
+    pub weight: usize,
+    pub backup: bool,
+    pub healthy: bool,
+    pub ejected: bool,
+    pub active_connections: usize,
+    pub tls: bool,
+}
+
+// a request in flight to a backend, the backend's connection count is
+// released when the guard is dropped with the request ctx
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    backend_states: Arc<HashMap<SocketAddr, BackendState>>,
+    addr: SocketAddr,
+}
+
+impl ConnectionGuard {
+    pub fn addr(&self) -> &SocketAddr {
+        &self.addr
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(state) = self.backend_states.get(&self.addr) {
+            state.active_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// the load balancer health check loop, ran on the runtime that built the
+// proxy pass and stopped once the last clone of the proxy pass is dropped
+struct HealthCheckTask(JoinHandle<()>);
+
+impl Drop for HealthCheckTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
 }
 
 impl TryFrom<&LocationToml> for ProxyPass {
     type Error = Error;
 
     fn try_from(location_toml: &LocationToml) -> Result<Self, Error> {
-        let mut proxy_passes_loadbalancer: LoadBalancer<RoundRobin> =
-            LoadBalancer::try_from_iter(&location_toml.proxy_passes)
+        let load_balancing = location_toml.load_balancing.unwrap_or_default();
+
+        let mut primaries = BTreeSet::new();
+        let mut backups = BTreeSet::new();
+        let mut backend_states = HashMap::new();
+        let mut backend_tls = HashMap::new();
+        let location_tls = location_toml
+            .tls
+            .as_ref()
+            .map(UpstreamTls::from_upstream_tls_toml)
+            .transpose()?;
+        for proxy_pass_toml in &location_toml.proxy_passes {
+            let addrs = proxy_pass_toml
+                .addr()
+                .to_socket_addrs()
                 .map_err(|err| Error::FailedToBuildLoadbalancer(err.to_string()))?;
+            let upstream_tls = match proxy_pass_toml.tls() {
+                Some(upstream_tls_toml) => {
+                    Some(UpstreamTls::from_upstream_tls_toml(upstream_tls_toml)?)
+                }
+                None => location_tls.clone(),
+            };
+            let host = proxy_pass_toml
+                .addr()
+                .rsplit_once(':')
+                .map_or(proxy_pass_toml.addr(), |(host, _)| host)
+                .trim_matches(['[', ']']);
+            for addr in addrs {
+                if let Some(upstream_tls) = &upstream_tls {
+                    backend_tls.insert(SocketAddr::Inet(addr), upstream_tls.for_host(host));
+                }
+                let backend = Backend {
+                    addr: SocketAddr::Inet(addr),
+                    weight: proxy_pass_toml.weight(),
+                    ext: Extensions::new(),
+                };
+                backend_states.insert(
+                    backend.addr.clone(),
+                    BackendState {
+                        active_connections: AtomicUsize::new(0),
+                        max_connections: proxy_pass_toml.max_connections(),
+                    },
+                );
+                match proxy_pass_toml.backup() {
+                    true => backups.insert(backend),
+                    false => primaries.insert(backend),
+                };
+            }
+        }
 
-        let proxy_passes_loadbalancer = if location_toml.health_check.unwrap_or(false) {
-            let hc = TcpHealthCheck::new();
-            proxy_passes_loadbalancer.set_health_check(hc);
-            proxy_passes_loadbalancer.health_check_frequency = Some(Duration::from_millis(
-                location_toml.health_check_frequency.unwrap_or(3000),
-            ));
-            let background = background_service(
-                &format!(
-                    "health check: {}",
-                    location_toml
-                        .endpoints
-                        .iter()
-                        .map(|e| e.path.clone())
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                ),
-                proxy_passes_loadbalancer,
-            );
-            background.task()
+        let backend_tls = Arc::new(backend_tls);
+        let mut health_check_tasks = Vec::new();
+        let (balancer, task) =
+            build_balancer(load_balancing, primaries, location_toml, &backend_tls)?;
+        health_check_tasks.extend(task);
+        let backup_balancer = if backups.is_empty() {
+            None
         } else {
-            Arc::new(proxy_passes_loadbalancer)
+            let (balancer, task) =
+                build_balancer(load_balancing, backups, location_toml, &backend_tls)?;
+            health_check_tasks.extend(task);
+            Some(balancer)
         };
 
+        let hash_key = match load_balancing {
+            LoadBalancingToml::Ketama => Some(location_toml.hash_key.clone().unwrap_or_default()),
+            _ => None,
+        };
+
+        let outlier_detector = location_toml
+            .outlier_detection
+            .as_ref()
+            .map(|e| Arc::new(OutlierDetector::new(e, backend_states.keys())));
+        let circuit_breaker = location_toml
+            .circuit_breaker
+            .as_ref()
+            .map(|e| Arc::new(CircuitBreaker::new(e)));
+
         let proxy_pass = ProxyPass {
-            addrs: location_toml.proxy_passes.clone(),
-            load_balancer: proxy_passes_loadbalancer,
+            addrs: location_toml
+                .proxy_passes
+                .iter()
+                .map(|e| e.addr().to_owned())
+                .collect(),
+            balancer,
+            backup_balancer,
+            hash_key,
+            backend_states: Arc::new(backend_states),
+            least_connections_offset: Arc::new(AtomicUsize::new(0)),
+            outlier_detector,
+            circuit_breaker,
+            retry_policy: location_toml
+                .retry
+                .as_ref()
+                .map(|e| Arc::new(RetryPolicy::from(e))),
+            timeouts: UpstreamTimeouts::from(location_toml.timeouts.as_ref()),
+            backend_tls,
+            health_check_tasks: Arc::new(health_check_tasks),
         };
 
         Ok(proxy_pass)
     }
 }
 
+fn build_balancer(
+    load_balancing: LoadBalancingToml,
+    backends: BTreeSet<Backend>,
+    location_toml: &LocationToml,
+    backend_tls: &Arc<HashMap<SocketAddr, UpstreamTls>>,
+) -> Result<(Balancer, Option<HealthCheckTask>), Error> {
+    let balancer = match load_balancing {
+        LoadBalancingToml::RoundRobin => {
+            let (load_balancer, task) = build_load_balancer(backends, location_toml, backend_tls)?;
+            (Balancer::RoundRobin(load_balancer), task)
+        }
+        LoadBalancingToml::Random => {
+            let (load_balancer, task) = build_load_balancer(backends, location_toml, backend_tls)?;
+            (Balancer::Random(load_balancer), task)
+        }
+        LoadBalancingToml::LeastConnections => {
+            let (load_balancer, task) = build_load_balancer(backends, location_toml, backend_tls)?;
+            (Balancer::LeastConnections(load_balancer), task)
+        }
+        LoadBalancingToml::Ketama => {
+            let (load_balancer, task) = build_load_balancer(backends, location_toml, backend_tls)?;
+            (Balancer::Ketama(load_balancer), task)
+        }
+    };
+    Ok(balancer)
+}
+
+fn build_load_balancer<S>(
+    backends: BTreeSet<Backend>,
+    location_toml: &LocationToml,
+    backend_tls: &Arc<HashMap<SocketAddr, UpstreamTls>>,
+) -> Result<(Arc<LoadBalancer<S>>, Option<HealthCheckTask>), Error>
+where
+    S: BackendSelection + Send + Sync + 'static,
+    S::Iter: BackendIter,
+{
+    let mut load_balancer: LoadBalancer<S> =
+        LoadBalancer::from_backends(Backends::new(Static::new(backends)));
+    // static discovery never awaits
+    load_balancer
+        .update()
+        .now_or_never()
+        .ok_or_else(|| Error::FailedToBuildLoadbalancer("backend discovery blocked".into()))?
+        .map_err(|err| Error::FailedToBuildLoadbalancer(err.to_string()))?;
+
+    if !location_toml.health_check.unwrap_or(false) {
+        return Ok((Arc::new(load_balancer), None));
+    }
+
+    match &location_toml.http_health_check {
+        Some(http_health_check_toml) => {
+            let hc = HttpHealthCheck::new(http_health_check_toml, backend_tls.clone())
+                .map_err(|err| Error::FailedToBuildHealthCheck(err.to_string()))?;
+            load_balancer.set_health_check(hc);
+        }
+        None => load_balancer.set_health_check(TcpHealthCheck::new()),
+    }
+    load_balancer.health_check_frequency = Some(Duration::from_millis(
+        location_toml.health_check_frequency.unwrap_or(3000),
+    ));
+    let load_balancer = Arc::new(load_balancer);
+
+    let health_checked = load_balancer.clone();
+    let task_handle = tokio::spawn(async move {
+        let (_shutdown_sender, shutdown) = watch::channel(false);
+        health_checked.run(shutdown, None).await;
+    });
+
+    Ok((load_balancer, Some(HealthCheckTask(task_handle))))
+}
+
+impl Balancer {
+    pub fn backends(&self) -> &Backends {
+        match self {
+            Balancer::RoundRobin(e) => e.backends(),
+            Balancer::Random(e) => e.backends(),
+            Balancer::LeastConnections(e) => e.backends(),
+            Balancer::Ketama(e) => e.backends(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Balancer::RoundRobin(_) => "round_robin",
+            Balancer::Random(_) => "random",
+            Balancer::LeastConnections(_) => "least_connections",
+            Balancer::Ketama(_) => "ketama",
+        }
+    }
+}
+
+impl ProxyPass {
+    // picks a healthy backend below its connection limit, falling back to the
+    // backups when every primary is down or full. the key is only used by ketama.
+    // backends in exclude (already tried by a retry) are only picked again when
+    // nothing else can take the request
+    pub fn select(&self, key: &[u8], exclude: &[SocketAddr]) -> Option<(Backend, ConnectionGuard)> {
+        let backend = self.select_primary_or_backup(key, exclude).or_else(|| {
+            if exclude.is_empty() {
+                None
+            } else {
+                self.select_primary_or_backup(key, &[])
+            }
+        })?;
+
+        let guard = ConnectionGuard {
+            backend_states: self.backend_states.clone(),
+            addr: backend.addr.clone(),
+        };
+        Some((backend, guard))
+    }
+
+    fn select_primary_or_backup(&self, key: &[u8], exclude: &[SocketAddr]) -> Option<Backend> {
+        self.select_from(&self.balancer, key, exclude).or_else(|| {
+            self.backup_balancer
+                .as_ref()
+                .and_then(|backup_balancer| self.select_from(backup_balancer, key, exclude))
+        })
+    }
+
+    // a returned backend already holds one of its connection slots
+    fn select_from(
+        &self,
+        balancer: &Balancer,
+        key: &[u8],
+        exclude: &[SocketAddr],
+    ) -> Option<Backend> {
+        let accept = |backend: &Backend, healthy: bool| {
+            healthy
+                && !exclude.contains(&backend.addr)
+                && !self.is_ejected(backend)
+                && self.try_acquire(backend)
+        };
+        match balancer {
+            Balancer::RoundRobin(e) => e.select_with(key, 256, accept),
+            Balancer::Random(e) => e.select_with(key, 256, accept),
+            Balancer::LeastConnections(e) => self.select_least_connections(e.backends(), exclude),
+            Balancer::Ketama(e) => e.select_with(key, 256, accept),
+        }
+    }
+
+    // picks the backend with the fewest connections per weight, the scan starts
+    // at a rotating offset so ties dont always go to the first backend
+    fn select_least_connections(
+        &self,
+        backends: &Backends,
+        exclude: &[SocketAddr],
+    ) -> Option<Backend> {
+        let mut candidates: Vec<Backend> = backends
+            .get_backend()
+            .iter()
+            .filter(|backend| {
+                backends.ready(backend)
+                    && !exclude.contains(&backend.addr)
+                    && !self.is_ejected(backend)
+            })
+            .cloned()
+            .collect();
+        let offset = self
+            .least_connections_offset
+            .fetch_add(1, Ordering::Relaxed);
+        if !candidates.is_empty() {
+            let len = candidates.len();
+            candidates.rotate_left(offset % len);
+        }
+
+        // another request can take the last slot between the sort and the
+        // acquire, so the next least loaded backend is tried after it
+        candidates.sort_by(|a, b| {
+            let a_load = (self.active_connections(&a.addr) + 1) * b.weight;
+            let b_load = (self.active_connections(&b.addr) + 1) * a.weight;
+            a_load.cmp(&b_load)
+        });
+        candidates
+            .into_iter()
+            .find(|backend| self.try_acquire(backend))
+    }
+
+    // why select found nothing and when to try again. "unhealthy" when no
+    // primary or backup passes its health check, "ejected" when outlier
+    // detection took out every healthy one, until the first comes back,
+    // and "max_connections" when the rest are full
+    pub fn unavailable(&self) -> (&'static str, Duration) {
+        let mut ejection_remaining: Option<Duration> = None;
+        let balancers = [&self.balancer].into_iter().chain(&self.backup_balancer);
+        for balancer in balancers {
+            let backends = balancer.backends();
+            for backend in backends.get_backend().iter() {
+                if !backends.ready(backend) {
+                    continue;
+                }
+                let remaining = self
+                    .outlier_detector
+                    .as_ref()
+                    .and_then(|e| e.ejection_remaining(&backend.addr));
+                match remaining {
+                    Some(remaining) => {
+                        ejection_remaining =
+                            Some(ejection_remaining.map_or(remaining, |e| e.min(remaining)));
+                    }
+                    None => return ("max_connections", UNAVAILABLE_RETRY_AFTER),
+                }
+            }
+        }
+        match ejection_remaining {
+            Some(remaining) => ("ejected", remaining),
+            None => ("unhealthy", UNAVAILABLE_RETRY_AFTER),
+        }
+    }
+
+    fn is_ejected(&self, backend: &Backend) -> bool {
+        self.outlier_detector
+            .as_ref()
+            .is_some_and(|e| e.is_ejected(&backend.addr))
+    }
+
+    // feeds a finished upstream attempt to the outlier detection
+    pub fn report_backend(&self, addr: &SocketAddr, success: bool) {
+        if let Some(outlier_detector) = &self.outlier_detector {
+            outlier_detector.report(addr, success);
+        }
+    }
+
+    fn try_acquire(&self, backend: &Backend) -> bool {
+        let Some(state) = self.backend_states.get(&backend.addr) else {
+            return true;
+        };
+        state
+            .active_connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                match state.max_connections {
+                    Some(max_connections) if active >= max_connections => None,
+                    _ => Some(active + 1),
+                }
+            })
+            .is_ok()
+    }
+
+    pub fn tls(&self, addr: &SocketAddr) -> Option<&UpstreamTls> {
+        self.backend_tls.get(addr)
+    }
+
+    pub fn active_connections(&self, addr: &SocketAddr) -> usize {
+        self.backend_states
+            .get(addr)
+            .map_or(0, |state| state.active_connections.load(Ordering::Relaxed))
+    }
+
+    pub fn load_balancing(&self) -> &'static str {
+        self.balancer.name()
+    }
+
+    // every primary and backup backend with its current health, backends
+    // without a health check are always reported as healthy
+    pub fn backends_health(&self) -> Vec<BackendHealth> {
+        let balancers = [(&self.balancer, false)]
+            .into_iter()
+            .chain(self.backup_balancer.iter().map(|e| (e, true)));
+
+        let mut backends_health = Vec::new();
+        for (balancer, backup) in balancers {
+            let backends = balancer.backends();
+            for backend in backends.get_backend().iter() {
+                backends_health.push(BackendHealth {
+                    addr: backend.addr.to_string(),
+                    weight: backend.weight,
+                    backup,
+                    healthy: backends.ready(backend),
+                    ejected: self.is_ejected(backend),
+                    active_connections: self.active_connections(&backend.addr),
+                    tls: self.backend_tls.contains_key(&backend.addr),
+                });
+            }
+        }
+        backends_health
+    }
+}
+
 impl fmt::Debug for ProxyPass {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ProxyPasses")
             .field("addrs", &self.addrs)
-            .field("load_balancer", &"<LoadBalancer<RoundRobin>>")
+            .field("load_balancing", &self.balancer.name())
+            .field("hash_key", &self.hash_key)
             .finish()
     }
 }
@@ -67,4 +513,10 @@ impl fmt::Debug for ProxyPass {
 pub enum Error {
     #[error("Failed to build a loadbalancer => {0}")]
     FailedToBuildLoadbalancer(String),
+
+    #[error("Failed to build a health check => {0}")]
+    FailedToBuildHealthCheck(String),
+
+    #[error("Invalid upstream tls => {0}")]
+    InvalidUpstreamTls(#[from] UpstreamTlsError),
 }
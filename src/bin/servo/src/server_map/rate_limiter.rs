@@ -6,14 +6,16 @@ use std::{net::IpAddr, time::Duration};
 pub struct RateLimiter {
     rate_limiter: Rate,
     max_req_sec: isize,
+    window: Duration,
 }
 
 impl RateLimiter {
     pub fn new(max_req_sec: isize) -> Self {
-        let rate = Rate::new(Duration::from_secs(1));
+        let window = Duration::from_secs(1);
         Self {
-            rate_limiter: rate,
+            rate_limiter: Rate::new(window),
             max_req_sec,
+            window,
         }
     }
 
@@ -22,6 +24,15 @@ impl RateLimiter {
         debug!("ratelimiter: {addr}:{current_window_req}");
         self.max_req_sec < current_window_req
     }
+
+    pub fn max_req_sec(&self) -> isize {
+        self.max_req_sec
+    }
+
+    // a limited client can retry once the current window is over
+    pub fn retry_after(&self) -> Duration {
+        self.window
+    }
 }
 
 impl fmt::Debug for RateLimiter {
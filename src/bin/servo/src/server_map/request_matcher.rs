@@ -0,0 +1,95 @@
+use std::collections::HashSet;
+
+use http::{HeaderName, Method};
+use pingora::http::RequestHeader;
+
+use crate::config_toml::{KeyValueMatchToml, RequestMatchToml};
+
+// the method, header, query and cookie conditions of a location, every
+// configured condition has to hold for the location to take a request
+#[derive(Debug)]
+pub struct RequestMatcher {
+    methods: Option<HashSet<Method>>,
+    headers: Vec<(HeaderName, Option<String>)>,
+    query: Vec<KeyValueMatchToml>,
+    cookies: Vec<KeyValueMatchToml>,
+}
+
+impl From<&RequestMatchToml> for RequestMatcher {
+    fn from(request_match_toml: &RequestMatchToml) -> Self {
+        Self {
+            methods: request_match_toml.methods.as_ref().map(|methods| {
+                methods
+                    .iter()
+                    .filter_map(|e| Method::from_bytes(e.to_uppercase().as_bytes()).ok())
+                    .collect()
+            }),
+            headers: request_match_toml
+                .headers
+                .iter()
+                .flatten()
+                .filter_map(|e| {
+                    let name = HeaderName::from_bytes(e.name.as_bytes()).ok()?;
+                    Some((name, e.value.clone()))
+                })
+                .collect(),
+            query: request_match_toml.query.clone().unwrap_or_default(),
+            cookies: request_match_toml.cookies.clone().unwrap_or_default(),
+        }
+    }
+}
+
+impl RequestMatcher {
+    pub fn matches(&self, req_header: &RequestHeader) -> bool {
+        if let Some(methods) = &self.methods
+            && !methods.contains(&req_header.method)
+        {
+            return false;
+        }
+
+        let headers_match = self.headers.iter().all(|(name, value)| {
+            let mut values = req_header.headers.get_all(name).iter();
+            match value {
+                Some(value) => values.any(|e| e.as_bytes() == value.as_bytes()),
+                None => values.next().is_some(),
+            }
+        });
+        if !headers_match {
+            return false;
+        }
+
+        let query = req_header.uri.query().unwrap_or_default();
+        let query_match = self.query.iter().all(|query_match| {
+            let mut values = url::form_urlencoded::parse(query.as_bytes())
+                .filter(|(name, _)| *name == query_match.name)
+                .map(|(_, value)| value);
+            match &query_match.value {
+                Some(value) => values.any(|e| e == *value),
+                None => values.next().is_some(),
+            }
+        });
+        if !query_match {
+            return false;
+        }
+
+        self.cookies.iter().all(
+            |cookie_match| match cookie(req_header, &cookie_match.name) {
+                Some(cookie) => cookie_match.value.as_ref().is_none_or(|e| e == cookie),
+                None => false,
+            },
+        )
+    }
+}
+
+// the value of the first cookie with the name
+pub fn cookie<'a>(req_header: &'a RequestHeader, name: &str) -> Option<&'a str> {
+    req_header
+        .headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .filter_map(|e| e.to_str().ok())
+        .flat_map(|e| e.split(';'))
+        .filter_map(|e| e.trim().split_once('='))
+        .find(|(cookie_name, _)| *cookie_name == name)
+        .map(|(_, value)| value)
+}
@@ -0,0 +1,81 @@
+use std::{collections::HashSet, time::Duration};
+
+use http::Method;
+
+use crate::config_toml::{RetryOnToml, RetryToml};
+
+#[derive(Debug)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    retry_on_connect_error: bool,
+    retry_on_timeout: bool,
+    retry_on_status: HashSet<u16>,
+    backoff: Duration,
+    max_backoff: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RetryableFailure {
+    ConnectError,
+    Timeout,
+    Status(u16),
+}
+
+impl From<&RetryToml> for RetryPolicy {
+    fn from(retry_toml: &RetryToml) -> Self {
+        let retry_on = retry_toml
+            .retry_on
+            .clone()
+            .unwrap_or(vec![RetryOnToml::ConnectError]);
+        let backoff = Duration::from_millis(retry_toml.backoff_ms.unwrap_or(0));
+
+        Self {
+            max_attempts: retry_toml.max_attempts,
+            retry_on_connect_error: retry_on.contains(&RetryOnToml::ConnectError),
+            retry_on_timeout: retry_on.contains(&RetryOnToml::Timeout),
+            retry_on_status: retry_toml
+                .retry_on_status
+                .iter()
+                .flatten()
+                .cloned()
+                .collect(),
+            backoff,
+            max_backoff: retry_toml
+                .max_backoff_ms
+                .map(Duration::from_millis)
+                .unwrap_or(backoff.saturating_mul(8)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // attempts is the number of tries already made for the request
+    pub fn should_retry(
+        &self,
+        failure: RetryableFailure,
+        method: &Method,
+        attempts: usize,
+    ) -> bool {
+        if attempts >= self.max_attempts {
+            return false;
+        }
+
+        let idempotent = method.is_idempotent();
+        match failure {
+            // nothing reached the backend, so even a POST is safe to send again
+            RetryableFailure::ConnectError => self.retry_on_connect_error,
+            RetryableFailure::Timeout => idempotent && self.retry_on_timeout,
+            RetryableFailure::Status(status) => {
+                idempotent && self.retry_on_status.contains(&status)
+            }
+        }
+    }
+
+    // the wait before the retry that follows the given number of attempts
+    pub fn backoff(&self, attempts: usize) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(16) as u32;
+        self.backoff
+            .saturating_mul(2u32.pow(exponent))
+            .min(self.max_backoff)
+    }
+}
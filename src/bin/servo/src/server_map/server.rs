@@ -2,86 +2,129 @@ use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
 
+use dashmap::DashMap;
 use fred::prelude::{ClientLike, Config, EventInterface, TcpConfig};
 use fred::types::Builder;
-use log::error;
 use matchit::Router;
 use thiserror::Error;
-use tokio::time::sleep;
+use tokio::time::timeout;
 
 use crate::public_pem::Error as PublicPemErr;
 use crate::redis_cache::RedisCache;
+use crate::server_map::client_cert::Error as ClientCertError;
+use crate::server_map::compression::Error as CompressionError;
+use crate::server_map::cors::Error as CorsError;
+use crate::server_map::error_pages::Error as ErrorPagesError;
+use crate::server_map::header_rules::Error as HeaderRulesError;
+use crate::server_map::https_redirect::Error as HttpsRedirectError;
 use crate::server_map::proxy_pass::Error as ProxyPassError;
+use crate::server_map::static_files::Error as StaticFilesError;
+use crate::server_map::static_response::Error as StaticResponseError;
 use crate::server_map::upstream::UpstreamCache;
-use crate::server_map::{RateLimiter, Upstream, UpstreamAuth};
+use crate::server_map::{
+    ClientCertRule, Compression, Cors, ErrorPages, HeaderRules, Hsts, HttpsRedirect, Maintenance,
+    Mirror, RateLimiter, Redirect, RequestMatcher, StaticFiles, StaticResponse, TrafficSplit,
+    Upstream, UpstreamAuth,
+};
 use crate::{config_toml::ServerToml, public_pem::PublicPemSync, server_map::ProxyPass};
 
+// a reload or admin change holds the config lock while the pem is fetched
+const PUBLIC_PEM_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct Server {
     pub name: String,
-    pub routes: Router<Arc<Upstream>>,
+    pub downstream_hosts: Vec<String>,
+    // takes the requests whose host matches no server
+    pub default_server: bool,
+    // the upstreams of every location sharing an endpoint, in config order
+    pub routes: Router<Vec<Arc<Upstream>>>,
+    // the same upstreams as in routes, keyed by their endpoint pattern
+    // since a matchit router cant be iterated
+    pub upstreams: Vec<(String, Arc<Upstream>)>,
+    pub error_pages: ErrorPages,
+    pub header_rules: Option<HeaderRules>,
+    pub cors: Option<Arc<Cors>>,
+    pub maintenance: Maintenance,
+    pub https_redirect: Option<HttpsRedirect>,
+    pub hsts: Option<Hsts>,
 }
 
 impl Server {
-    pub async fn from_server_toml(server_toml: &ServerToml) -> Result<Self, Error> {
-        let mut router = Router::new();
+    // caches holds the redis clients of earlier builds by url, they are reused
+    // since the cache storage has to live for the whole process
+    pub async fn from_server_toml(
+        server_toml: &ServerToml,
+        caches: &DashMap<String, &'static RedisCache>,
+    ) -> Result<Self, Error> {
+        let mut upstreams: Vec<(String, Arc<Upstream>)> = Vec::new();
 
         let public_key_sync = match &server_toml.auth {
             Some(auth_toml) => {
-                let public_pem_sync = loop {
-                    let public_pem_sync = PublicPemSync::init_auth_toml(auth_toml).await;
-                    let public_pem_sync = match public_pem_sync {
-                        Ok(e) => e,
-                        Err(PublicPemErr::FailedToFetchPublicPem(err)) => {
-                            error!(
-                                "failed to fetch public pem {err}, retrying in 10 secs, blocking till successfull"
-                            );
-                            sleep(Duration::from_secs(10)).await;
-                            continue;
-                        }
-                        Err(err) => panic!("failed to get public pem: {err}"),
-                    };
-                    break public_pem_sync;
-                };
-
+                let public_pem_sync =
+                    timeout(PUBLIC_PEM_TIMEOUT, PublicPemSync::init_auth_toml(auth_toml))
+                        .await
+                        .map_err(|_| Error::PublicPemTimeout(PUBLIC_PEM_TIMEOUT.as_secs()))??;
                 Some(Arc::new(public_pem_sync))
             }
             None => None,
         };
 
         let redis_pool = match server_toml.cache {
-            Some(ref e) => {
-                let config = Config::from_url(e.url.as_str()).expect("invalid cache url");
-
-                let redis_pool = Builder::from_config(config)
-                    .with_connection_config(|config| {
-                        config.connection_timeout = Duration::from_secs(5);
-                        config.tcp = TcpConfig {
-                            nodelay: Some(true),
-                            ..Default::default()
-                        };
-                    })
-                    .build()
-                    .map_err(|e| Error::RedisClient(e.to_string()))?;
-
-                redis_pool
-                    .init()
-                    .await
-                    .map_err(|e| Error::RedisConn(e.to_string()))?;
-
-                redis_pool.on_error(|(error, server)| async move {
-                    println!("Redis connection error {:?}: {:?}", server, error);
-                    Ok(())
-                });
-
-                let cache = Box::leak(Box::new(RedisCache::new(redis_pool))) as &'static RedisCache;
-
-                Some(cache)
-            }
+            Some(ref e) => Some(redis_cache(e.url.as_str(), caches).await?),
             None => None,
         };
 
         for location_toml in &server_toml.locations {
+            let proxy_pass = ProxyPass::try_from(location_toml)?;
+            let traffic_split = match &location_toml.split {
+                Some(split) => Some(Arc::new(TrafficSplit::from_location_toml(
+                    location_toml,
+                    split,
+                )?)),
+                None => None,
+            };
+            let header_rules = match &location_toml.headers {
+                Some(headers) => Some(Arc::new(HeaderRules::from_header_rules_toml(headers)?)),
+                None => None,
+            };
+            let cors = match &location_toml.cors {
+                Some(cors) => Some(Arc::new(Cors::from_cors_toml(cors)?)),
+                None => None,
+            };
+            let compression = match &location_toml.compression {
+                Some(compression) => {
+                    Some(Arc::new(Compression::from_compression_toml(compression)?))
+                }
+                None => None,
+            };
+            let static_response = match &location_toml.respond {
+                Some(respond) => Some(Arc::new(StaticResponse::from_static_response_toml(
+                    respond,
+                )?)),
+                None => None,
+            };
+            let redirect = match &location_toml.redirect {
+                Some(redirect) => Some(Arc::new(Redirect::from_redirect_toml(redirect)?)),
+                None => None,
+            };
+            let static_files = match &location_toml.static_files {
+                Some(static_files) => {
+                    Some(Arc::new(StaticFiles::from_static_files_toml(static_files)?))
+                }
+                None => None,
+            };
+            let client_cert = match &location_toml.client_cert {
+                Some(client_cert) => Some(Arc::new(ClientCertRule::from_client_cert_toml(
+                    client_cert,
+                )?)),
+                None => None,
+            };
+            let mirror = match &location_toml.mirror {
+                Some(mirror) => Some(Arc::new(Mirror::from_location_toml(location_toml, mirror)?)),
+                None => None,
+            };
+
             let rate_limiter = location_toml
                 .max_requests_per_sec
                 .map(|e| Arc::new(RateLimiter::new(e as isize)));
@@ -96,7 +139,6 @@ impl Server {
             }
 
             for endpoint in location_toml.endpoints.clone() {
-                let proxy_pass = ProxyPass::try_from(location_toml)?;
                 let url_concat_suffix = compute_base_endpoint(&endpoint.path);
 
                 let jwt_allowed_roles =
@@ -134,35 +176,151 @@ impl Server {
                 });
 
                 let upstream = Upstream {
+                    endpoint: endpoint.path.clone(),
                     url_concat_suffix,
-                    proxy_pass,
+                    proxy_pass: proxy_pass.clone(),
                     rate_limiter,
                     blacklisted_endpoints,
                     auth: upstream_auth,
                     cache: upstream_cache,
                     reroute_template: endpoint.reroute,
+                    matcher: location_toml.matches.as_ref().map(RequestMatcher::from),
+                    traffic_split: traffic_split.clone(),
+                    mirror: mirror.clone(),
+                    header_rules: header_rules.clone(),
+                    cors: cors.clone(),
+                    compression: compression.clone(),
+                    static_response: static_response.clone(),
+                    redirect: redirect.clone(),
+                    static_files: static_files.clone(),
+                    client_cert: client_cert.clone(),
                 };
 
-                router
-                    .insert(endpoint.path, Arc::new(upstream))
-                    .map_err(|err| Error::FailedToInsertIntoRouter(err.to_string()))?;
+                upstreams.push((endpoint.path, Arc::new(upstream)));
+            }
+        }
+
+        let mut endpoints: Vec<(&String, Vec<Arc<Upstream>>)> = Vec::new();
+        for (endpoint, upstream) in &upstreams {
+            match endpoints.iter_mut().find(|(e, _)| *e == endpoint) {
+                Some((_, shared)) => shared.push(upstream.clone()),
+                None => endpoints.push((endpoint, vec![upstream.clone()])),
             }
         }
+        let mut router = Router::new();
+        for (endpoint, shared) in endpoints {
+            router
+                .insert(endpoint.clone(), shared)
+                .map_err(|err| Error::FailedToInsertIntoRouter(err.to_string()))?;
+        }
 
         let server = Server {
             name: server_toml.name.clone(),
+            downstream_hosts: server_toml.downstream_hosts.clone(),
+            default_server: server_toml.default_server.unwrap_or(false),
             routes: router,
+            upstreams,
+            error_pages: ErrorPages::from_error_pages_toml(
+                server_toml.error_pages.as_deref().unwrap_or_default(),
+            )?,
+            header_rules: server_toml
+                .headers
+                .as_ref()
+                .map(HeaderRules::from_header_rules_toml)
+                .transpose()?,
+            cors: server_toml
+                .cors
+                .as_ref()
+                .map(Cors::from_cors_toml)
+                .transpose()?
+                .map(Arc::new),
+            maintenance: Maintenance::from_maintenance_toml(server_toml.maintenance.as_ref())?,
+            https_redirect: server_toml
+                .https_redirect
+                .as_ref()
+                .map(HttpsRedirect::from_https_redirect_toml)
+                .transpose()?,
+            hsts: server_toml
+                .hsts
+                .as_ref()
+                .map(Hsts::from_hsts_toml)
+                .transpose()?,
         };
 
         Ok(server)
     }
 }
 
+async fn redis_cache(
+    url: &str,
+    caches: &DashMap<String, &'static RedisCache>,
+) -> Result<&'static RedisCache, Error> {
+    if let Some(cache) = caches.get(url) {
+        return Ok(*cache);
+    }
+
+    let config = Config::from_url(url).map_err(|e| Error::RedisClient(e.to_string()))?;
+    let redis_pool = Builder::from_config(config)
+        .with_connection_config(|config| {
+            config.connection_timeout = Duration::from_secs(5);
+            config.tcp = TcpConfig {
+                nodelay: Some(true),
+                ..Default::default()
+            };
+        })
+        .build()
+        .map_err(|e| Error::RedisClient(e.to_string()))?;
+
+    redis_pool
+        .init()
+        .await
+        .map_err(|e| Error::RedisConn(e.to_string()))?;
+
+    redis_pool.on_error(|(error, server)| async move {
+        println!("Redis connection error {:?}: {:?}", server, error);
+        Ok(())
+    });
+
+    let cache = Box::leak(Box::new(RedisCache::new(redis_pool))) as &'static RedisCache;
+    caches.insert(url.to_owned(), cache);
+    Ok(cache)
+}
+
 #[derive(Error, Debug)]
 pub enum Error {
     #[error("Failed to build proxypass => {0}")]
     FailedToBuildProxyPass(#[from] ProxyPassError),
 
+    #[error("Invalid error pages => {0}")]
+    InvalidErrorPages(#[from] ErrorPagesError),
+
+    #[error("Invalid header rules => {0}")]
+    InvalidHeaderRules(#[from] HeaderRulesError),
+
+    #[error("Invalid cors => {0}")]
+    InvalidCors(#[from] CorsError),
+
+    #[error("Invalid https redirect or hsts => {0}")]
+    InvalidHttpsRedirect(#[from] HttpsRedirectError),
+
+    #[error("Invalid client cert => {0}")]
+    InvalidClientCert(#[from] ClientCertError),
+
+    #[error("Invalid compression => {0}")]
+    InvalidCompression(#[from] CompressionError),
+
+    #[error("Invalid static response => {0}")]
+    InvalidStaticResponse(#[from] StaticResponseError),
+
+    #[error("Invalid static files => {0}")]
+    InvalidStaticFiles(#[from] StaticFilesError),
+
+    #[error("Failed to get public pem => {0}")]
+    PublicPem(#[from] PublicPemErr),
+
+    #[error("Timed out fetching the public pem after {0} secs")]
+    PublicPemTimeout(u64),
+
     #[error("Failed to insert into router => {0}")]
     FailedToInsertIntoRouter(String),
 
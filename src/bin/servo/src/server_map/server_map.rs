@@ -1,38 +1,256 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use dashmap::DashMap;
+use log::{error, warn};
+use servo_toml::FormatValidate;
+use thiserror::Error;
+use tokio::{
+    sync::Mutex,
+    time::{sleep, timeout},
+};
 
 use crate::{
     ConfigToml,
-    server_map::{DownStreamHost, Server},
+    config_toml::{LocationToml, MaintenanceToml, ServerToml},
+    public_pem::Error as PublicPemErr,
+    redis_cache::RedisCache,
+    server_map::{
+        DownStreamHost, HostRouter, Server, host_router::Error as HostRouterError,
+        server::Error as ServerError,
+    },
 };
 
+// the config lock is held while servers build, so a reload or admin change
+// that hangs on the auth server or redis must not block the ones after it
+const SERVER_BUILD_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub struct ServerMap {
-    // a router hashmap that contains the key: downstream host
-    // and val: endpoint router with the proxy pass for that downstream
-    pub routes: DashMap<DownStreamHost, Arc<Server>>,
+    // maps the downstream host to the server with its endpoint router,
+    // swapped as a whole whenever the servers change
+    hosts: RwLock<HostRouter>,
+    // the same servers as in routes, keyed by the server name
+    pub servers: DashMap<String, Arc<Server>>,
+    // the config the live routes were built from, every change to the
+    // map goes through it so its always validated as a whole
+    config_toml: Mutex<ConfigToml>,
+    // redis clients by url, shared by every build of the servers
+    caches: DashMap<String, &'static RedisCache>,
 }
 
 impl ServerMap {
     pub async fn build_from_config_toml(config: &ConfigToml) -> Self {
-        let routes = DashMap::new();
+        let servers = DashMap::new();
+        let caches = DashMap::new();
         for server_toml in &config.servers {
-            let server = match Server::from_server_toml(server_toml).await {
+            // nothing serves yet, so startup waits for the auth server
+            let server = loop {
+                match Server::from_server_toml(server_toml, &caches).await {
+                    Err(
+                        e @ (ServerError::PublicPemTimeout(_)
+                        | ServerError::PublicPem(PublicPemErr::FailedToFetchPublicPem(_))),
+                    ) => {
+                        error!("{e}, retrying in 10 secs, blocking till successfull");
+                        sleep(Duration::from_secs(10)).await;
+                    }
+                    e => break e,
+                }
+            };
+            let server = match server {
                 Ok(server) => Arc::new(server),
                 Err(e) => {
                     eprintln!("Failed to create server from config: {}", e);
                     continue;
                 }
             };
+            servers.insert(server_toml.name.clone(), server);
+        }
+        let hosts = servers
+            .iter()
+            .map(|e| e.value().clone())
+            .collect::<Vec<_>>();
+        let hosts = HostRouter::new(hosts.iter()).expect("host patterns are validated");
+        Self {
+            hosts: RwLock::new(hosts),
+            servers,
+            caches,
+            config_toml: Mutex::new(config.clone()),
+        }
+    }
+
+    pub fn route(&self, host: &DownStreamHost) -> Option<Arc<Server>> {
+        self.hosts.read().unwrap().route(host)
+    }
+
+    pub async fn config_toml(&self) -> ConfigToml {
+        self.config_toml.lock().await.clone()
+    }
 
-            for downstream_host_toml in &server_toml.downstream_hosts {
-                routes.insert(
-                    DownStreamHost::from(downstream_host_toml.to_owned()),
-                    server.clone(),
-                );
+    // the [config] table only takes effect on startup, so it is kept as is
+    pub async fn reload_from_config_toml(&self, mut config: ConfigToml) -> Result<(), Error> {
+        let mut config_toml = self.config_toml.lock().await;
+        if config.config != config_toml.config {
+            warn!("changes to the [config] table are ignored on reload and require a restart");
+            config.config = config_toml.config.clone();
+        }
+        self.apply_config_toml(&mut config_toml, config).await
+    }
+
+    pub async fn upsert_server(&self, server_toml: ServerToml) -> Result<(), Error> {
+        let mut config_toml = self.config_toml.lock().await;
+        let mut config = config_toml.clone();
+        match config
+            .servers
+            .iter_mut()
+            .find(|e| e.name == server_toml.name)
+        {
+            Some(e) => *e = server_toml,
+            None => config.servers.push(server_toml),
+        }
+        self.apply_config_toml(&mut config_toml, config).await
+    }
+
+    pub async fn remove_server(&self, server_name: &str) -> Result<(), Error> {
+        let mut config_toml = self.config_toml.lock().await;
+        let mut config = config_toml.clone();
+        let len = config.servers.len();
+        config.servers.retain(|e| e.name != server_name);
+        if config.servers.len() == len {
+            return Err(Error::ServerNotFound(server_name.to_owned()));
+        }
+        self.apply_config_toml(&mut config_toml, config).await
+    }
+
+    // replaces the location at index, or appends it when index is None
+    pub async fn upsert_location(
+        &self,
+        server_name: &str,
+        index: Option<usize>,
+        location_toml: LocationToml,
+    ) -> Result<(), Error> {
+        let mut config_toml = self.config_toml.lock().await;
+        let mut config = config_toml.clone();
+        let server_toml = config
+            .servers
+            .iter_mut()
+            .find(|e| e.name == server_name)
+            .ok_or_else(|| Error::ServerNotFound(server_name.to_owned()))?;
+
+        match index {
+            Some(index) => {
+                let location = server_toml
+                    .locations
+                    .get_mut(index)
+                    .ok_or_else(|| Error::LocationNotFound(server_name.to_owned(), index))?;
+                *location = location_toml;
             }
+            None => server_toml.locations.push(location_toml),
+        }
+        self.apply_config_toml(&mut config_toml, config).await
+    }
+
+    pub async fn remove_location(&self, server_name: &str, index: usize) -> Result<(), Error> {
+        let mut config_toml = self.config_toml.lock().await;
+        let mut config = config_toml.clone();
+        let server_toml = config
+            .servers
+            .iter_mut()
+            .find(|e| e.name == server_name)
+            .ok_or_else(|| Error::ServerNotFound(server_name.to_owned()))?;
+
+        if index >= server_toml.locations.len() {
+            return Err(Error::LocationNotFound(server_name.to_owned(), index));
         }
-        Self { routes }
+        server_toml.locations.remove(index);
+        self.apply_config_toml(&mut config_toml, config).await
+    }
+
+    // flips the switch on the live server without rebuilding it, the config
+    // is updated too so the server counts as unchanged on the next apply
+    pub async fn set_maintenance(&self, server_name: &str, enabled: bool) -> Result<(), Error> {
+        let mut config_toml = self.config_toml.lock().await;
+        let server_toml = config_toml
+            .servers
+            .iter_mut()
+            .find(|e| e.name == server_name)
+            .ok_or_else(|| Error::ServerNotFound(server_name.to_owned()))?;
+        let server = self
+            .servers
+            .get(server_name)
+            .ok_or_else(|| Error::ServerNotFound(server_name.to_owned()))?;
+
+        server_toml
+            .maintenance
+            .get_or_insert_with(MaintenanceToml::default)
+            .enabled = Some(enabled);
+        server.maintenance.set_enabled(enabled);
+        Ok(())
     }
+
+    // validates the new config and builds every changed server before touching
+    // the live routes, so a server that fails to build leaves the old map serving.
+    // unchanged servers are reused and requests already in flight keep their own
+    // Arc<Server> / Arc<Upstream>
+    async fn apply_config_toml(
+        &self,
+        current_config: &mut ConfigToml,
+        new_config: ConfigToml,
+    ) -> Result<(), Error> {
+        new_config.validate().map_err(Error::InvalidConfig)?;
+
+        let mut new_servers = Vec::new();
+        for server_toml in &new_config.servers {
+            let unchanged = current_config.servers.contains(server_toml);
+            let server = match self.servers.get(&server_toml.name) {
+                Some(e) if unchanged => e.clone(),
+                _ => {
+                    let server = timeout(
+                        SERVER_BUILD_TIMEOUT,
+                        Server::from_server_toml(server_toml, &self.caches),
+                    )
+                    .await
+                    .map_err(|_| Error::BuildTimedOut(server_toml.name.clone()))??;
+                    Arc::new(server)
+                }
+            };
+            new_servers.push(server);
+        }
+
+        let hosts = HostRouter::new(new_servers.iter())?;
+        *self.hosts.write().unwrap() = hosts;
+
+        let new_names: HashSet<String> = new_servers.iter().map(|e| e.name.clone()).collect();
+        for server in new_servers {
+            self.servers.insert(server.name.clone(), server);
+        }
+        self.servers.retain(|name, _| new_names.contains(name));
+
+        *current_config = new_config;
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid config => {0}")]
+    InvalidConfig(String),
+
+    #[error("Failed to build server => {0}")]
+    FailedToBuildServer(#[from] ServerError),
+
+    #[error("Timed out building server '{0}'")]
+    BuildTimedOut(String),
+
+    #[error("Invalid downstream host => {0}")]
+    InvalidDownstreamHost(#[from] HostRouterError),
+
+    #[error("There is no server named '{0}'")]
+    ServerNotFound(String),
+
+    #[error("Server '{0}' has no location at index {1}")]
+    LocationNotFound(String, usize),
 }
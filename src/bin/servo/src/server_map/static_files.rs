@@ -0,0 +1,382 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use bytes::Bytes;
+use http::{HeaderValue, Method, header};
+use percent_encoding::percent_decode_str;
+use pingora::{
+    Error as PingoraError,
+    ErrorType::HTTPStatus,
+    Result,
+    http::{RequestHeader, ResponseHeader},
+};
+use thiserror::Error;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{
+    config_toml::StaticFilesToml,
+    server_map::{Encoding, preferred_encoding},
+};
+
+const DEFAULT_INDEX: &str = "index.html";
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+// serves the files under a directory, the file path is taken from an
+// endpoint path param
+#[derive(Debug)]
+pub struct StaticFiles {
+    root: PathBuf,
+    path_param: Option<String>,
+    index: Vec<String>,
+    spa_fallback: bool,
+    precompressed: bool,
+    cache_control: Option<HeaderValue>,
+}
+
+pub struct ServedFile {
+    pub resp: ResponseHeader,
+    // None for responses without a body like a 304 or 416
+    pub body: Option<FileBody>,
+}
+
+pub struct FileBody {
+    file: File,
+    remaining: u64,
+}
+
+impl FileBody {
+    pub fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    pub async fn read_chunk(&mut self) -> std::io::Result<Option<Bytes>> {
+        if self.remaining == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0; self.remaining.min(CHUNK_SIZE) as usize];
+        let read = self.file.read(&mut buf).await?;
+        if read == 0 {
+            // the file shrank since it was opened
+            self.remaining = 0;
+            return Ok(None);
+        }
+        buf.truncate(read);
+        self.remaining -= read as u64;
+        Ok(Some(buf.into()))
+    }
+}
+
+impl StaticFiles {
+    pub fn from_static_files_toml(static_files_toml: &StaticFilesToml) -> Result<Self, Error> {
+        let root = std::fs::canonicalize(&static_files_toml.root).map_err(|err| {
+            Error::InvalidRoot(
+                static_files_toml.root.display().to_string(),
+                err.to_string(),
+            )
+        })?;
+        if !root.is_dir() {
+            return Err(Error::InvalidRoot(
+                root.display().to_string(),
+                "not a directory".into(),
+            ));
+        }
+
+        if static_files_toml
+            .index
+            .as_ref()
+            .is_some_and(|e| e.is_empty())
+        {
+            return Err(Error::NoIndex);
+        }
+
+        let cache_control = static_files_toml
+            .cache_control
+            .as_ref()
+            .map(|e| HeaderValue::from_str(e).map_err(|_| Error::InvalidCacheControl(e.clone())))
+            .transpose()?;
+
+        Ok(Self {
+            root,
+            path_param: static_files_toml.path_param.clone(),
+            index: static_files_toml
+                .index
+                .clone()
+                .unwrap_or_else(|| vec![DEFAULT_INDEX.into()]),
+            spa_fallback: static_files_toml.spa_fallback.unwrap_or(false),
+            precompressed: static_files_toml.precompressed.unwrap_or(true),
+            cache_control,
+        })
+    }
+
+    pub async fn serve(
+        &self,
+        req_header: &RequestHeader,
+        path_params: &HashMap<String, String>,
+    ) -> Result<ServedFile> {
+        if req_header.method != Method::GET && req_header.method != Method::HEAD {
+            let mut resp = ResponseHeader::build(405, Some(2))?;
+            resp.insert_header(header::ALLOW, "GET, HEAD")?;
+            resp.set_content_length(0)?;
+            return Ok(ServedFile { resp, body: None });
+        }
+
+        let relative = match &self.path_param {
+            Some(path_param) => path_params.get(path_param),
+            None if path_params.len() == 1 => path_params.values().next(),
+            None => None,
+        };
+        let relative = relative.map(String::as_str).unwrap_or_default();
+        let Some(mut path) = self.resolve(relative) else {
+            return Err(not_found());
+        };
+
+        let mut metadata = fs::metadata(&path).await.ok();
+        if metadata.as_ref().is_some_and(|e| e.is_dir()) {
+            // relative links in the index only work below the directory
+            let req_path = req_header.uri.path();
+            if !req_path.ends_with('/') {
+                let location = match req_header.uri.query() {
+                    Some(query) => format!("{req_path}/?{query}"),
+                    None => format!("{req_path}/"),
+                };
+                let mut resp = ResponseHeader::build(301, Some(2))?;
+                resp.insert_header(header::LOCATION, location)?;
+                resp.set_content_length(0)?;
+                return Ok(ServedFile { resp, body: None });
+            }
+            metadata = None;
+            for index in &self.index {
+                let index_path = path.join(index);
+                if let Ok(index_metadata) = fs::metadata(&index_path).await
+                    && index_metadata.is_file()
+                {
+                    path = index_path;
+                    metadata = Some(index_metadata);
+                    break;
+                }
+            }
+        }
+
+        if !metadata.as_ref().is_some_and(|e| e.is_file()) {
+            // client side routes have no extension, missing assets still 404
+            let is_route = relative.rsplit('/').next().is_none_or(|e| !e.contains('.'));
+            if !self.spa_fallback || !is_route {
+                return Err(not_found());
+            }
+            path = self.root.join(&self.index[0]);
+        }
+
+        // symlinks may not lead out of the root
+        let path = match fs::canonicalize(&path).await {
+            Ok(e) if e.starts_with(&self.root) => e,
+            _ => return Err(not_found()),
+        };
+        self.file_response(req_header, &path).await
+    }
+
+    // the path under the root, None when it tries to leave it
+    fn resolve(&self, relative: &str) -> Option<PathBuf> {
+        let decoded = percent_decode_str(relative).decode_utf8().ok()?;
+        let mut path = self.root.clone();
+        for component in decoded.split('/') {
+            match component {
+                "" | "." => continue,
+                ".." => return None,
+                e if e.contains(['\\', '\0']) => return None,
+                e => path.push(e),
+            }
+        }
+        Some(path)
+    }
+
+    async fn file_response(&self, req_header: &RequestHeader, path: &Path) -> Result<ServedFile> {
+        let content_type = mime_guess::from_path(path).first_or_octet_stream();
+
+        // a .br / .gz next to the file is sent as is to clients that accept it
+        let mut encoded = Vec::new();
+        if self.precompressed {
+            for (encoding, extension) in [(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")] {
+                let mut encoded_path = path.as_os_str().to_owned();
+                encoded_path.push(".");
+                encoded_path.push(extension);
+                if let Ok(encoded_path) = fs::canonicalize(PathBuf::from(encoded_path)).await
+                    && encoded_path.starts_with(&self.root)
+                    && fs::metadata(&encoded_path).await.is_ok_and(|e| e.is_file())
+                {
+                    encoded.push((encoding, encoded_path));
+                }
+            }
+        }
+        let candidates: Vec<Encoding> = encoded.iter().map(|(e, _)| *e).collect();
+        let (encoding, path) = match preferred_encoding(req_header, &candidates) {
+            Some(encoding) => {
+                let (_, encoded_path) = encoded.into_iter().find(|(e, _)| *e == encoding).unwrap();
+                (Some(encoding), encoded_path)
+            }
+            None => (None, path.to_path_buf()),
+        };
+
+        let mut file = File::open(&path).await.map_err(internal_error)?;
+        let metadata = file.metadata().await.map_err(internal_error)?;
+        let len = metadata.len();
+        let modified = metadata.modified().ok();
+        let modified_secs = modified
+            .and_then(|e| e.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |e| e.as_secs());
+        let etag = format!(
+            "\"{modified_secs:x}-{len:x}{}\"",
+            encoding.map_or(String::new(), |e| format!("-{}", e.as_str()))
+        );
+        let last_modified = modified.map(httpdate::fmt_http_date);
+
+        let mut resp = ResponseHeader::build(200, Some(8))?;
+        resp.insert_header(header::ETAG, &etag)?;
+        if let Some(last_modified) = &last_modified {
+            resp.insert_header(header::LAST_MODIFIED, last_modified)?;
+        }
+        if let Some(cache_control) = &self.cache_control {
+            resp.insert_header(header::CACHE_CONTROL, cache_control)?;
+        }
+        if self.precompressed {
+            resp.insert_header(header::VARY, "Accept-Encoding")?;
+        }
+
+        if not_modified(req_header, &etag, modified_secs) {
+            resp.set_status(304)?;
+            return Ok(ServedFile { resp, body: None });
+        }
+
+        resp.insert_header(header::CONTENT_TYPE, content_type.as_ref())?;
+        resp.insert_header(header::ACCEPT_RANGES, "bytes")?;
+        if let Some(encoding) = encoding {
+            resp.insert_header(header::CONTENT_ENCODING, encoding.as_str())?;
+        }
+
+        let range = req_header
+            .headers
+            .get(header::RANGE)
+            .filter(|_| if_range_matches(req_header, &etag, last_modified.as_deref()))
+            .and_then(|e| e.to_str().ok())
+            .and_then(|e| parse_range(e, len));
+        let (start, end) = match range {
+            Some(Ok((start, end))) => {
+                resp.set_status(206)?;
+                resp.insert_header(header::CONTENT_RANGE, format!("bytes {start}-{end}/{len}"))?;
+                (start, end)
+            }
+            Some(Err(())) => {
+                resp.set_status(416)?;
+                resp.insert_header(header::CONTENT_RANGE, format!("bytes */{len}"))?;
+                resp.set_content_length(0)?;
+                return Ok(ServedFile { resp, body: None });
+            }
+            None if len == 0 => {
+                resp.set_content_length(0)?;
+                return Ok(ServedFile { resp, body: None });
+            }
+            None => (0, len - 1),
+        };
+
+        let remaining = end - start + 1;
+        resp.set_content_length(remaining as usize)?;
+        if start > 0 {
+            file.seek(SeekFrom::Start(start))
+                .await
+                .map_err(internal_error)?;
+        }
+        Ok(ServedFile {
+            resp,
+            body: Some(FileBody { file, remaining }),
+        })
+    }
+}
+
+// If-None-Match wins over If-Modified-Since, etags are compared weakly
+fn not_modified(req_header: &RequestHeader, etag: &str, modified_secs: u64) -> bool {
+    if let Some(if_none_match) = req_header.headers.get(header::IF_NONE_MATCH) {
+        let if_none_match = if_none_match.to_str().unwrap_or_default();
+        return if_none_match.split(',').any(|e| {
+            let e = e.trim();
+            e == "*" || e.trim_start_matches("W/") == etag
+        });
+    }
+    req_header
+        .headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|e| e.to_str().ok())
+        .and_then(|e| httpdate::parse_http_date(e).ok())
+        .and_then(|e| e.duration_since(UNIX_EPOCH).ok())
+        .is_some_and(|since| modified_secs <= since.as_secs())
+}
+
+// a range whose If-Range no longer matches gets the whole file
+fn if_range_matches(req_header: &RequestHeader, etag: &str, last_modified: Option<&str>) -> bool {
+    let Some(if_range) = req_header.headers.get(header::IF_RANGE) else {
+        return true;
+    };
+    let if_range = if_range.to_str().unwrap_or_default().trim();
+    if if_range.starts_with('"') {
+        return if_range == etag;
+    }
+    let since = httpdate::parse_http_date(if_range).ok();
+    since.is_some() && since == last_modified.and_then(|e| httpdate::parse_http_date(e).ok())
+}
+
+// a single "bytes=" range as inclusive offsets, Err when it cant be satisfied
+// and None when the header is ignored
+fn parse_range(range: &str, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    if start.is_empty() {
+        let suffix: u64 = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok((len.saturating_sub(suffix), len - 1)));
+    }
+
+    let start: u64 = start.parse().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse::<u64>().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= len {
+        return Some(Err(()));
+    }
+    Some(Ok((start, end.map_or(len - 1, |end| end.min(len - 1)))))
+}
+
+fn not_found() -> Box<PingoraError> {
+    PingoraError::explain(HTTPStatus(404), "Not Found")
+}
+
+fn internal_error(err: std::io::Error) -> Box<PingoraError> {
+    PingoraError::because(HTTPStatus(500), "failed to read a static file", err)
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Invalid static files root {0} => {1}")]
+    InvalidRoot(String, String),
+
+    #[error("The static files index list cant be empty")]
+    NoIndex,
+
+    #[error("Invalid cache control '{0}'")]
+    InvalidCacheControl(String),
+}
@@ -0,0 +1,160 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use bytes::Bytes;
+use http::{HeaderName, HeaderValue, StatusCode, header};
+use pingora::{Result, http::ResponseHeader};
+use thiserror::Error;
+
+use crate::config_toml::{RedirectToml, StaticResponseToml};
+
+const DEFAULT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+// a fixed answer for locations that never need a backend
+#[derive(Debug)]
+pub struct StaticResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Bytes,
+}
+
+impl StaticResponse {
+    pub fn from_static_response_toml(
+        static_response_toml: &StaticResponseToml,
+    ) -> Result<Self, Error> {
+        Self::new(
+            static_response_toml.status.unwrap_or(200),
+            static_response_toml.headers.as_ref(),
+            static_response_toml.content_type.as_deref(),
+            static_response_toml.body.as_deref(),
+            static_response_toml.body_path.as_deref(),
+        )
+    }
+
+    pub fn new(
+        status: u16,
+        headers: Option<&HashMap<String, String>>,
+        content_type: Option<&str>,
+        body: Option<&str>,
+        body_path: Option<&Path>,
+    ) -> Result<Self, Error> {
+        if !(200..=599).contains(&status) {
+            return Err(Error::InvalidStatus(status));
+        }
+        let status = StatusCode::from_u16(status).map_err(|_| Error::InvalidStatus(status))?;
+
+        let body = match (body, body_path) {
+            (Some(body), _) => Bytes::from(body.to_owned()),
+            (None, Some(body_path)) => fs::read(body_path).map(Bytes::from).map_err(|err| {
+                Error::FailedToReadBody(body_path.display().to_string(), err.to_string())
+            })?,
+            (None, None) => Bytes::new(),
+        };
+        if matches!(status.as_u16(), 204 | 304) && !body.is_empty() {
+            return Err(Error::UnexpectedBody(status.as_u16()));
+        }
+
+        let mut parsed_headers = Vec::new();
+        if let Some(content_type) =
+            content_type.or((!body.is_empty()).then_some(DEFAULT_CONTENT_TYPE))
+        {
+            parsed_headers.push((header::CONTENT_TYPE, parse_value(content_type)?));
+        }
+        for (name, value) in headers.into_iter().flatten() {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| Error::InvalidHeader(name.clone()))?;
+            parsed_headers.push((name, parse_value(value)?));
+        }
+
+        Ok(Self {
+            status,
+            headers: parsed_headers,
+            body,
+        })
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn response(&self) -> Result<(ResponseHeader, Bytes)> {
+        let mut resp = ResponseHeader::build(self.status, Some(self.headers.len() + 1))?;
+        for (name, value) in &self.headers {
+            resp.append_header(name, value)?;
+        }
+        // a 204 or 304 has no body and must not say otherwise
+        if !matches!(self.status.as_u16(), 204 | 304) {
+            resp.set_content_length(self.body.len())?;
+        }
+        Ok((resp, self.body.clone()))
+    }
+}
+
+// a redirect whose target is filled from the endpoint path params
+#[derive(Debug)]
+pub struct Redirect {
+    status: StatusCode,
+    to: String,
+    keep_query: bool,
+}
+
+impl Redirect {
+    pub fn from_redirect_toml(redirect_toml: &RedirectToml) -> Result<Self, Error> {
+        let status = redirect_toml.status.unwrap_or(302);
+        if !matches!(status, 301 | 302 | 307 | 308) {
+            return Err(Error::InvalidRedirectStatus(status));
+        }
+        Ok(Self {
+            status: StatusCode::from_u16(status).map_err(|_| Error::InvalidStatus(status))?,
+            to: redirect_toml.to.clone(),
+            keep_query: redirect_toml.keep_query.unwrap_or(true),
+        })
+    }
+
+    pub fn response(
+        &self,
+        path_params: &HashMap<String, String>,
+        query: Option<&str>,
+    ) -> Result<ResponseHeader> {
+        let mut location = self.to.clone();
+        for (key, value) in path_params {
+            location = location.replace(&format!("{{{key}}}"), value);
+            location = location.replace(&format!("{{*{key}}}"), value);
+        }
+        if self.keep_query
+            && let Some(query) = query
+        {
+            let separator = if location.contains('?') { '&' } else { '?' };
+            location = format!("{location}{separator}{query}");
+        }
+
+        let mut resp = ResponseHeader::build(self.status, Some(2))?;
+        resp.insert_header(header::LOCATION, location)?;
+        resp.set_content_length(0)?;
+        Ok(resp)
+    }
+}
+
+fn parse_value(value: &str) -> Result<HeaderValue, Error> {
+    HeaderValue::from_str(value).map_err(|_| Error::InvalidHeaderValue(value.to_owned()))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read the response body at {0} => {1}")]
+    FailedToReadBody(String, String),
+
+    #[error("Invalid response status {0}")]
+    InvalidStatus(u16),
+
+    #[error("A {0} response cant have a body")]
+    UnexpectedBody(u16),
+
+    #[error("Invalid redirect status {0}, expected 301, 302, 307 or 308")]
+    InvalidRedirectStatus(u16),
+
+    #[error("Invalid header name '{0}'")]
+    InvalidHeader(String),
+
+    #[error("Invalid header value '{0}'")]
+    InvalidHeaderValue(String),
+}
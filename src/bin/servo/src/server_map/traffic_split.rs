@@ -0,0 +1,85 @@
+use http::HeaderName;
+
+use crate::{
+    config_toml::{DEFAULT_VARIANT, HashKeyToml, LocationToml, TrafficSplitToml},
+    server_map::{ProxyPass, proxy_pass::Error as ProxyPassError},
+};
+
+const DEFAULT_OVERRIDE_HEADER: &str = "x-servo-variant";
+
+// splits a location's traffic between backend groups, every variant is a
+// proxy pass of its own with its own balancer, health checks and breaker
+#[derive(Debug)]
+pub struct TrafficSplit {
+    pub sticky_key: HashKeyToml,
+    pub override_header: HeaderName,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug)]
+pub struct Variant {
+    pub name: String,
+    pub percent: u32,
+    pub proxy_pass: ProxyPass,
+}
+
+impl TrafficSplit {
+    pub fn from_location_toml(
+        location_toml: &LocationToml,
+        traffic_split_toml: &TrafficSplitToml,
+    ) -> Result<Self, ProxyPassError> {
+        let mut variants = Vec::new();
+        for variant_toml in &traffic_split_toml.variants {
+            // the variant shares every location setting but the backends
+            let mut variant_location = location_toml.clone();
+            variant_location.proxy_passes = variant_toml.proxy_passes.clone();
+            variants.push(Variant {
+                name: variant_toml.name.clone(),
+                percent: variant_toml.percent,
+                proxy_pass: ProxyPass::try_from(&variant_location)?,
+            });
+        }
+
+        let override_header = traffic_split_toml
+            .override_header
+            .as_deref()
+            .unwrap_or(DEFAULT_OVERRIDE_HEADER);
+        Ok(Self {
+            sticky_key: traffic_split_toml.sticky_key.clone().unwrap_or_default(),
+            override_header: HeaderName::from_bytes(override_header.as_bytes())
+                .unwrap_or(HeaderName::from_static(DEFAULT_OVERRIDE_HEADER)),
+            variants,
+        })
+    }
+
+    // the index of the variant for a request, None is the default variant.
+    // the key is hashed into a bucket out of 100 so the same key always
+    // lands on the same variant while the percents dont change
+    pub fn pick(&self, key: &[u8], override_variant: Option<&str>) -> Option<usize> {
+        if let Some(name) = override_variant {
+            if name == DEFAULT_VARIANT {
+                return None;
+            }
+            if let Some(index) = self.variants.iter().position(|e| e.name == name) {
+                return Some(index);
+            }
+        }
+
+        let bucket = (fnv1a(key) % 100) as u32;
+        let mut upper = 0;
+        for (index, variant) in self.variants.iter().enumerate() {
+            upper += variant.percent;
+            if bucket < upper {
+                return Some(index);
+            }
+        }
+        None
+    }
+}
+
+// stable across restarts and builds, unlike the std hasher
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
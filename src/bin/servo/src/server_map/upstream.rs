@@ -1,14 +1,17 @@
 use std::{collections::HashSet, sync::Arc};
 
-use fred::prelude::Client;
-
 use crate::{
+    config_toml::DEFAULT_VARIANT,
     redis_cache::RedisCache,
-    server_map::{ProxyPass, RateLimiter, UpstreamAuth},
+    server_map::{
+        ClientCertRule, Compression, Cors, HeaderRules, Mirror, ProxyPass, RateLimiter, Redirect,
+        RequestMatcher, StaticFiles, StaticResponse, TrafficSplit, UpstreamAuth,
+    },
 };
 
 #[derive(Debug)]
 pub struct Upstream {
+    pub endpoint: String,
     pub url_concat_suffix: String,
     pub reroute_template: Option<String>,
     pub proxy_pass: ProxyPass,
@@ -16,6 +19,41 @@ pub struct Upstream {
     pub cache: Option<UpstreamCache>,
     pub blacklisted_endpoints: HashSet<String>,
     pub rate_limiter: Option<Arc<RateLimiter>>,
+    // None takes every request routed to the endpoint
+    pub matcher: Option<RequestMatcher>,
+    pub traffic_split: Option<Arc<TrafficSplit>>,
+    pub mirror: Option<Arc<Mirror>>,
+    pub header_rules: Option<Arc<HeaderRules>>,
+    pub cors: Option<Arc<Cors>>,
+    pub compression: Option<Arc<Compression>>,
+    // set for locations that answer without a backend
+    pub static_response: Option<Arc<StaticResponse>>,
+    pub redirect: Option<Arc<Redirect>>,
+    pub static_files: Option<Arc<StaticFiles>>,
+    pub client_cert: Option<Arc<ClientCertRule>>,
+}
+
+impl Upstream {
+    // the proxy pass of a split variant, None is the location's own
+    pub fn variant_proxy_pass(&self, variant: Option<usize>) -> &ProxyPass {
+        match (&self.traffic_split, variant) {
+            (Some(split), Some(index)) => &split.variants[index].proxy_pass,
+            _ => &self.proxy_pass,
+        }
+    }
+
+    // the proxy pass of every variant by variant name, the location's own
+    // proxy pass first
+    pub fn proxy_passes(&self) -> impl Iterator<Item = (&str, &ProxyPass)> {
+        let variants = self
+            .traffic_split
+            .iter()
+            .flat_map(|e| e.variants.iter())
+            .map(|e| (e.name.as_str(), &e.proxy_pass));
+        [(DEFAULT_VARIANT, &self.proxy_pass)]
+            .into_iter()
+            .chain(variants)
+    }
 }
 
 #[derive(Debug)]
@@ -0,0 +1,59 @@
+use std::time::{Duration, Instant};
+
+use pingora::prelude::HttpPeer;
+
+use crate::config_toml::TimeoutsToml;
+
+#[derive(Debug, Clone, Copy)]
+pub struct UpstreamTimeouts {
+    pub connect: Duration,
+    pub tls_handshake: Option<Duration>,
+    pub read: Option<Duration>,
+    pub write: Option<Duration>,
+    pub idle: Option<Duration>,
+    pub total: Option<Duration>,
+}
+
+impl From<Option<&TimeoutsToml>> for UpstreamTimeouts {
+    fn from(timeouts_toml: Option<&TimeoutsToml>) -> Self {
+        let millis = |e: Option<u64>| e.map(Duration::from_millis);
+        match timeouts_toml {
+            Some(e) => Self {
+                connect: millis(e.connect_ms).unwrap_or(Duration::from_millis(100)),
+                tls_handshake: millis(e.tls_handshake_ms),
+                read: millis(e.read_ms),
+                write: millis(e.write_ms),
+                idle: millis(e.idle_ms),
+                total: millis(e.total_ms),
+            },
+            None => Self {
+                connect: Duration::from_millis(100),
+                tls_handshake: None,
+                read: None,
+                write: None,
+                idle: None,
+                total: None,
+            },
+        }
+    }
+}
+
+impl UpstreamTimeouts {
+    // sets the peer timeouts, cut down to what is left of the total timeout
+    pub fn apply(&self, peer: &mut HttpPeer, deadline: Option<Instant>) {
+        let remaining = deadline.map(|e| e.saturating_duration_since(Instant::now()));
+        let clamp = |timeout: Option<Duration>| match (timeout, remaining) {
+            (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+            (timeout, remaining) => timeout.or(remaining),
+        };
+
+        peer.options.connection_timeout = clamp(Some(self.connect));
+        peer.options.total_connection_timeout = clamp(
+            self.tls_handshake
+                .map(|tls_handshake| self.connect + tls_handshake),
+        );
+        peer.options.read_timeout = clamp(self.read);
+        peer.options.write_timeout = clamp(self.write);
+        peer.options.idle_timeout = self.idle;
+    }
+}
@@ -0,0 +1,89 @@
+use std::{fs, path::Path, sync::Arc};
+
+use openssl::{pkey::PKey, x509::X509};
+use pingora::{prelude::HttpPeer, protocols::ALPN, upstreams::peer::Scheme, utils::tls::CertKey};
+use thiserror::Error;
+
+use crate::config_toml::UpstreamTlsToml;
+
+// the tls settings of a single backend, the ca and client cert are
+// shared by every backend that uses the same toml
+#[derive(Debug, Clone)]
+pub struct UpstreamTls {
+    pub sni: String,
+    pub verify: bool,
+    pub http2: bool,
+    ca: Option<Arc<Box<[X509]>>>,
+    client_cert_key: Option<Arc<CertKey>>,
+}
+
+impl UpstreamTls {
+    pub fn from_upstream_tls_toml(upstream_tls_toml: &UpstreamTlsToml) -> Result<Self, Error> {
+        let ca = match &upstream_tls_toml.ca_path {
+            Some(ca_path) => {
+                let ca = X509::stack_from_pem(&read_file(ca_path)?).map_err(|err| {
+                    Error::InvalidPem(ca_path.display().to_string(), err.to_string())
+                })?;
+                Some(Arc::new(ca.into_boxed_slice()))
+            }
+            None => None,
+        };
+
+        let client_cert_key = match (
+            &upstream_tls_toml.client_cert_path,
+            &upstream_tls_toml.client_key_path,
+        ) {
+            (Some(cert_path), Some(key_path)) => {
+                let certs = X509::stack_from_pem(&read_file(cert_path)?).map_err(|err| {
+                    Error::InvalidPem(cert_path.display().to_string(), err.to_string())
+                })?;
+                let key = PKey::private_key_from_pem(&read_file(key_path)?).map_err(|err| {
+                    Error::InvalidPem(key_path.display().to_string(), err.to_string())
+                })?;
+                Some(Arc::new(CertKey::new(certs, key)))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            sni: upstream_tls_toml.sni.clone().unwrap_or_default(),
+            verify: upstream_tls_toml.verify.unwrap_or(true),
+            http2: upstream_tls_toml.http2.unwrap_or(false),
+            ca,
+            client_cert_key,
+        })
+    }
+
+    // the same settings for a backend, with the sni defaulted to its host
+    pub fn for_host(&self, host: &str) -> Self {
+        let mut upstream_tls = self.clone();
+        if upstream_tls.sni.is_empty() {
+            upstream_tls.sni = host.to_owned();
+        }
+        upstream_tls
+    }
+
+    pub fn apply(&self, peer: &mut HttpPeer) {
+        peer.scheme = Scheme::HTTPS;
+        peer.sni = self.sni.clone();
+        peer.options.verify_cert = self.verify;
+        peer.options.verify_hostname = self.verify;
+        peer.options.ca = self.ca.clone();
+        peer.client_cert_key = self.client_cert_key.clone();
+        peer.options.alpn = if self.http2 { ALPN::H2H1 } else { ALPN::H1 };
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    fs::read(path)
+        .map_err(|err| Error::FailedToReadFile(path.display().to_string(), err.to_string()))
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read '{0}' => {1}")]
+    FailedToReadFile(String, String),
+
+    #[error("Invalid pem in '{0}' => {1}")]
+    InvalidPem(String, String),
+}
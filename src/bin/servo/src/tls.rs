@@ -1,15 +1,22 @@
+use async_trait::async_trait;
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
 use openssl::ssl::{
-    AlpnError, NameType, SniError, SslAlert, SslContext, SslFiletype, SslMethod, SslRef,
-    select_next_proto,
+    AlpnError, NameType, SniError, SslAlert, SslContext, SslContextBuilder, SslFiletype, SslMethod,
+    SslOptions, SslRef, SslVerifyMode, SslVersion, select_next_proto,
 };
-use rustls_pemfile::{Item, read_one};
+use openssl::x509::{X509, X509Name, X509Ref};
+use pingora::listeners::{TlsAccept, tls::TlsSettings};
 use serde::Deserialize;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::BufReader;
-use x509_parser::extensions::GeneralName;
-use x509_parser::nom::Err as NomErr;
-use x509_parser::prelude::*;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config_toml::{ClientAuthToml, TlsListenToml};
+use crate::server_map::ClientCert;
 
 #[derive(Clone, Deserialize, Debug)]
 pub(crate) struct CertificateConfig {
@@ -17,69 +24,214 @@ pub(crate) struct CertificateConfig {
     pub key_path: String,
 }
 
+// a cert and key pair as loaded from its files
 #[derive(Debug)]
-struct CertificateInfo {
-    common_names: Vec<String>,
-    alt_names: Vec<String>,
-    ssl_context: SslContext,
-    #[allow(dead_code)]
-    cert_path: String, // Only used for logging
-    #[allow(dead_code)]
-    key_path: String, // Only used for logging
+struct CertificatePair {
+    cert_path: String,
+    key_path: String,
+    // the certificate of the key and the rest of the chain in file order
+    leaf: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+    // lowercase dns names the leaf is valid for
+    names: Vec<String>,
+    // unix timestamp of the leaf certificate notAfter
+    not_after: i64,
+    // the mtimes of the cert and key files when they were last read
+    modified: (Option<SystemTime>, Option<SystemTime>),
+}
+
+impl CertificatePair {
+    fn load(cert_path: &str, key_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let modified = files_modified(cert_path, key_path);
+        let key = PKey::private_key_from_pem(&std::fs::read(key_path)?)?;
+        let mut chain = X509::stack_from_pem(&std::fs::read(cert_path)?)?;
+        if chain.is_empty() {
+            return Err("no pem certificate in the file".into());
+        }
+        // a cert rotated before its key must not load with the old key
+        let leaf = chain
+            .iter()
+            .position(|e| e.public_key().is_ok_and(|e| e.public_eq(&key)))
+            .ok_or("no certificate in the file matches the private key")?;
+        let leaf = chain.remove(leaf);
+
+        Ok(Self {
+            cert_path: cert_path.to_owned(),
+            key_path: key_path.to_owned(),
+            names: certificate_names(&leaf),
+            not_after: unix_timestamp(leaf.not_after())?,
+            leaf,
+            chain,
+            key,
+            modified,
+        })
+    }
+
+    // fills the slot of the key type, so an ecdsa and an rsa pair can be
+    // set on the same connection and openssl picks what the client supports
+    fn set_on(&self, ssl_ref: &mut SslRef) -> Result<(), ErrorStack> {
+        ssl_ref.set_certificate(&self.leaf)?;
+        ssl_ref.set_private_key(&self.key)?;
+        for cert in &self.chain {
+            ssl_ref.add_chain_cert(cert.clone())?;
+        }
+        Ok(())
+    }
+}
+
+// server names to the pairs serving them, the first pair of each key type
+// in config order wins a name
+#[derive(Debug, Default)]
+struct CertificateIndex {
+    exact: HashMap<String, Vec<usize>>,
+    // keyed by the name without its "*." label
+    wildcard: HashMap<String, Vec<usize>>,
+    // served without sni or for names no certificate covers
+    default: Vec<usize>,
+}
+
+impl CertificateIndex {
+    fn new(pairs: &[CertificatePair]) -> Self {
+        let mut index = Self::default();
+        for (i, pair) in pairs.iter().enumerate() {
+            for name in &pair.names {
+                // only a whole left-most label can be a wildcard and it has to
+                // leave at least two labels, "*.com" or "w*.example.com" match nothing
+                let pairs_of_name = match name.strip_prefix("*.") {
+                    Some(parent) if parent.contains('.') && !parent.contains('*') => {
+                        index.wildcard.entry(parent.to_owned()).or_default()
+                    }
+                    None if !name.contains('*') => index.exact.entry(name.clone()).or_default(),
+                    _ => continue,
+                };
+                if !pairs_of_name
+                    .iter()
+                    .any(|e| *e == i || pairs[*e].key.id() == pair.key.id())
+                {
+                    pairs_of_name.push(i);
+                }
+            }
+        }
+
+        index.default = pairs[0]
+            .names
+            .first()
+            .and_then(|e| index.lookup(e))
+            .map_or(vec![0], |e| e.to_vec());
+        index
+    }
+
+    // exact names before wildcards, a wildcard covers exactly one label (rfc 6125)
+    fn lookup(&self, server_name: &str) -> Option<&[usize]> {
+        if let Some(pairs) = self.exact.get(server_name) {
+            return Some(pairs);
+        }
+        let (label, parent) = server_name.split_once('.')?;
+        if label.is_empty() {
+            return None;
+        }
+        self.wildcard.get(parent).map(|e| e.as_slice())
+    }
 }
 
+#[derive(Debug)]
+struct CertificateStore {
+    pairs: Vec<CertificatePair>,
+    index: CertificateIndex,
+}
+
+// the certificates of one tls listener, swapped in place when their files change
 #[derive(Debug)]
 pub(crate) struct Certificates {
-    configs: Vec<CertificateInfo>,
+    store: RwLock<CertificateStore>,
+    // alpn and client auth without any certificate, every connection is
+    // switched to it before the pairs picked by sni are set on it
+    base_context: SslContext,
     pub(crate) default_cert_path: String,
     pub(crate) default_key_path: String,
 }
 
 impl Certificates {
-    pub(crate) fn new(configs: &Vec<CertificateConfig>) -> Self {
+    pub(crate) fn new(
+        configs: &Vec<CertificateConfig>,
+        context_settings: &ContextSettings,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let default_cert = configs
             .first()
-            .expect("atleast one TLS certificate required");
-        let mut cert_infos = Vec::new();
+            .ok_or("atleast one TLS certificate required")?;
+        let mut pairs = Vec::new();
         for config in configs {
-            cert_infos.push(
-                load_cert_info(&config.cert_path, &config.key_path).unwrap_or_else(|| {
-                    panic!(
-                        "unable to load certificate info | public: {}, private: {}",
+            let pair =
+                CertificatePair::load(&config.cert_path, &config.key_path).map_err(|err| {
+                    format!(
+                        "unable to load certificate | public: {}, private: {} => {err}",
                         &config.cert_path, &config.key_path
                     )
-                }),
-            );
+                })?;
+            pairs.push(pair);
         }
-        Self {
-            configs: cert_infos,
+        let index = CertificateIndex::new(&pairs);
+
+        Ok(Self {
+            store: RwLock::new(CertificateStore { pairs, index }),
+            base_context: create_ssl_context(context_settings)?,
             default_cert_path: default_cert.cert_path.clone(),
             default_key_path: default_cert.key_path.clone(),
-        }
+        })
     }
 
-    fn find_ssl_context(&self, server_name: &str) -> Option<&SslContext> {
-        for config in &self.configs {
-            // Exact name match
-            if config.common_names.contains(&server_name.to_string())
-                || config.alt_names.contains(&server_name.to_string())
-            {
-                return Some(&config.ssl_context);
+    // rereads the pairs whose files changed, a pair that fails to load keeps
+    // the old certificate until its files change again
+    pub(crate) fn reload_changed(&self) {
+        let mut store = self.store.write().unwrap();
+        let store = &mut *store;
+        let mut reloaded = false;
+        for pair in store.pairs.iter_mut() {
+            let modified = files_modified(&pair.cert_path, &pair.key_path);
+            if modified == pair.modified {
+                continue;
             }
-
-            // Wildcard match
-            for name in &config.common_names {
-                if name.starts_with("*.") && server_name.ends_with(&name[1..]) {
-                    return Some(&config.ssl_context);
+            pair.modified = modified;
+            match CertificatePair::load(&pair.cert_path, &pair.key_path) {
+                Ok(new_pair) => {
+                    log::info!("reloaded tls certificate {}", pair.cert_path);
+                    *pair = new_pair;
+                    reloaded = true;
                 }
+                Err(err) => log::error!(
+                    "failed to reload tls certificate {}, keeping the old one => {err}",
+                    pair.cert_path
+                ),
             }
-            for name in &config.alt_names {
-                if name.starts_with("*.") && server_name.ends_with(&name[1..]) {
-                    return Some(&config.ssl_context);
-                }
+        }
+        if reloaded {
+            store.index = CertificateIndex::new(&store.pairs);
+        }
+    }
+
+    // the cert path and notAfter timestamp of every loaded certificate
+    pub(crate) fn expiries(&self) -> Vec<(String, i64)> {
+        self.store
+            .read()
+            .unwrap()
+            .pairs
+            .iter()
+            .map(|e| (e.cert_path.clone(), e.not_after))
+            .collect()
+    }
+
+    pub(crate) fn warn_expiring(&self, warning: Duration) {
+        let now = chrono::Utc::now().timestamp();
+        for (cert_path, not_after) in self.expiries() {
+            let secs_left = not_after - now;
+            if secs_left <= 0 {
+                log::warn!("tls certificate {cert_path} has expired");
+            } else if secs_left < warning.as_secs() as i64 {
+                let days_left = secs_left / 86_400;
+                log::warn!("tls certificate {cert_path} expires in {days_left} days");
             }
         }
-        None
     }
 
     pub(crate) fn server_name_callback(
@@ -89,7 +241,7 @@ impl Certificates {
     ) -> Result<(), SniError> {
         // Turns out SNI is really complicated
 
-        let server_name = ssl_ref.servername(NameType::HOST_NAME);
+        let server_name = ssl_ref.servername(NameType::HOST_NAME).map(normalize_name);
 
         log::info!(
             "TLS connect: server_name = {:?}, ssl_ref = {:?}, ssl_alert = {:?}",
@@ -98,113 +250,209 @@ impl Certificates {
             ssl_alert
         );
 
-        // Attempt to set the SSL context if a server name is provided and matches an entry in the map
-        if let Some(name) = server_name {
-            match self.find_ssl_context(name) {
-                Some(ctx) => {
-                    // We've managed to retrieve a context for a server name we're aware of
-                    // If we can't set the context, we'll return an ALERT_FATAL
-                    ssl_ref
-                        .set_ssl_context(ctx)
-                        .map_err(|_| SniError::ALERT_FATAL)?;
-                }
-                None => {
-                    log::info!("No matching server name found");
-                }
+        let store = self.store.read().unwrap();
+        // The server name doesn't exist, or it doesn't match any certs we're expecting, serve a default cert.
+        // openssl calls this without sni too, so a reloaded default cert is picked up
+        let pairs = match server_name.as_deref().and_then(|e| store.index.lookup(e)) {
+            Some(pairs) => pairs,
+            None => {
+                log::info!("No matching server name found");
+                &store.index.default
             }
-        }
+        };
 
-        // The server name doesn't exist, or it doesn't match any certs we're expecting, serve a default cert
+        // If we can't set the certificates, we'll return an ALERT_FATAL
+        ssl_ref
+            .set_ssl_context(&self.base_context)
+            .map_err(|_| SniError::ALERT_FATAL)?;
+        for pair in pairs {
+            store.pairs[*pair]
+                .set_on(ssl_ref)
+                .map_err(|_| SniError::ALERT_FATAL)?;
+        }
         Ok(())
     }
 }
 
-fn load_cert_info(cert_path: &str, key_path: &str) -> Option<CertificateInfo> {
-    let mut common_names = HashSet::new();
-    let mut alt_names = HashSet::new();
+// dns sans, the common name only counts for certificates without any (rfc 6125)
+fn certificate_names(cert: &X509Ref) -> Vec<String> {
+    let mut names: Vec<String> = cert
+        .subject_alt_names()
+        .iter()
+        .flatten()
+        .filter_map(|e| e.dnsname().map(normalize_name))
+        .collect();
+    if names.is_empty() {
+        names.extend(
+            cert.subject_name()
+                .entries_by_nid(Nid::COMMONNAME)
+                .filter_map(|e| e.data().as_utf8().ok())
+                .map(|e| normalize_name(&e)),
+        );
+    }
+    names
+}
 
-    let file = File::open(cert_path);
-    match file {
-        Err(e) => {
-            log::error!("Failed to open certificate file: {:?}", e);
-            return None;
+// names are compared case insensitively and without a trailing dot
+fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+fn unix_timestamp(time: &Asn1TimeRef) -> Result<i64, ErrorStack> {
+    let diff = Asn1Time::from_unix(0)?.diff(time)?;
+    Ok(diff.days as i64 * 86_400 + diff.secs as i64)
+}
+
+fn create_ssl_context(context_settings: &ContextSettings) -> Result<SslContext, ErrorStack> {
+    let mut ctx = SslContext::builder(SslMethod::tls())?;
+    // the alpn callback and client ca of the context picked by sni are the ones used
+    let alpn = context_settings.alpn.clone();
+    ctx.set_alpn_select_callback(move |_, alpn_in| select_alpn(&alpn, alpn_in));
+    if let Some(client_auth) = &context_settings.client_auth {
+        set_client_auth(&mut ctx, client_auth)?;
+    }
+    let built = ctx.build();
+    Ok(built)
+}
+
+fn files_modified(cert_path: &str, key_path: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+    let modified = |path| std::fs::metadata(path).and_then(|e| e.modified()).ok();
+    (modified(cert_path), modified(key_path))
+}
+
+// the settings of the base context of a listener, openssl takes these from
+// the context sni switched to
+#[derive(Debug)]
+pub(crate) struct ContextSettings {
+    alpn: Vec<u8>,
+    client_auth: Option<ClientAuthToml>,
+}
+
+// an optional client certificate is verified when sent, a failed
+// verification fails the handshake either way
+fn set_client_auth(
+    ctx: &mut SslContextBuilder,
+    client_auth: &ClientAuthToml,
+) -> Result<(), ErrorStack> {
+    ctx.set_ca_file(&client_auth.ca_path)?;
+    ctx.set_client_ca_list(X509Name::load_client_ca_file(&client_auth.ca_path)?);
+    let mut verify_mode = SslVerifyMode::PEER;
+    if client_auth.required.unwrap_or(false) {
+        verify_mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+    }
+    ctx.set_verify(verify_mode);
+    // session resumption is refused without one once peers are verified
+    ctx.set_session_id_context(b"servo")?;
+    Ok(())
+}
+
+// hands the verified client certificate to the http layer through the
+// connection digest
+struct ClientCertAccept;
+
+#[async_trait]
+impl TlsAccept for ClientCertAccept {
+    async fn handshake_complete_callback(
+        &self,
+        ssl: &SslRef,
+    ) -> Option<Arc<dyn Any + Send + Sync>> {
+        let client_cert = ClientCert::from_ssl(ssl)?;
+        Some(Arc::new(client_cert))
+    }
+}
+
+// the settings of one tls listener, certificates are picked by sni
+pub(crate) fn tls_settings(
+    tls_listen: &TlsListenToml,
+) -> Result<(TlsSettings, Arc<Certificates>), Box<dyn std::error::Error>> {
+    let mut certificate_configs: Vec<CertificateConfig> = Vec::new();
+    for tls_toml in &tls_listen.certificates {
+        certificate_configs.push(CertificateConfig {
+            cert_path: tls_toml.cert_path.to_str().unwrap().to_owned(),
+            key_path: tls_toml.key_path.to_str().unwrap().to_owned(),
+        });
+    }
+    let alpn = alpn_wire(tls_listen.alpn.as_deref());
+    let context_settings = ContextSettings {
+        alpn: alpn.clone(),
+        client_auth: tls_listen.client_auth.clone(),
+    };
+    let certificates = Arc::new(Certificates::new(&certificate_configs, &context_settings)?);
+
+    let mut tls_settings = match &tls_listen.client_auth {
+        Some(client_auth) => {
+            let mut tls_settings = TlsSettings::with_callbacks(Box::new(ClientCertAccept))?;
+            tls_settings.set_private_key_file(&certificates.default_key_path, SslFiletype::PEM)?;
+            tls_settings.set_certificate_chain_file(&certificates.default_cert_path)?;
+            set_client_auth(&mut tls_settings, client_auth)?;
+            tls_settings
         }
-        Ok(file) => {
-            let mut reader = BufReader::new(file);
-            match read_one(&mut reader) {
-                Err(e) => {
-                    log::error!("Failed to decode PEM from certificate file: {:?}", e);
-                    return None;
-                }
-                Ok(leaf) => match leaf {
-                    Some(Item::X509Certificate(cert)) => match X509Certificate::from_der(&cert) {
-                        Err(NomErr::Error(e)) | Err(NomErr::Failure(e)) => {
-                            log::error!("Failed to parse certificate: {:?}", e);
-                            return None;
-                        }
-                        Err(_) => {
-                            log::error!("Unknown error while parsing certificate");
-                            return None;
-                        }
-                        Ok((_, x509)) => {
-                            let subject = x509.subject();
-                            for attr in subject.iter_common_name() {
-                                if let Ok(cn) = attr.as_str() {
-                                    common_names.insert(cn.to_string());
-                                }
-                            }
-
-                            if let Ok(Some(san)) = x509.subject_alternative_name() {
-                                for name in san.value.general_names.iter() {
-                                    if let GeneralName::DNSName(dns) = name {
-                                        let dns_string = dns.to_string();
-                                        if !common_names.contains(&dns_string) {
-                                            alt_names.insert(dns_string);
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    },
-                    _ => {
-                        log::error!("Failed to read certificate");
-                        return None;
-                    }
-                },
-            }
+        None => TlsSettings::intermediate(
+            &certificates.default_cert_path,
+            &certificates.default_key_path,
+        )?,
+    };
+    let sni_certificates = certificates.clone();
+    tls_settings.set_servername_callback(move |ssl_ref: &mut SslRef, ssl_alert: &mut SslAlert| {
+        sni_certificates.server_name_callback(ssl_ref, ssl_alert)
+    });
+    tls_settings.set_alpn_select_callback(move |_, alpn_in| select_alpn(&alpn, alpn_in));
+
+    if tls_listen.min_version.as_deref() == Some("1.3") {
+        tls_settings.set_min_proto_version(Some(SslVersion::TLS1_3))?;
+    }
+
+    if let Some(cipher_suites) = &tls_listen.cipher_suites {
+        let (tls13, tls12): (Vec<&str>, Vec<&str>) = cipher_suites
+            .iter()
+            .map(|e| e.as_str())
+            .partition(|e| e.starts_with("TLS_"));
+        if !tls12.is_empty() {
+            tls_settings.set_cipher_list(&tls12.join(":"))?;
+        }
+        if !tls13.is_empty() {
+            tls_settings.set_ciphersuites(&tls13.join(":"))?;
         }
     }
 
-    if let Ok(ssl_context) = create_ssl_context(cert_path, key_path) {
-        Some(CertificateInfo {
-            cert_path: cert_path.to_string(),
-            key_path: key_path.to_string(),
-            common_names: common_names.into_iter().collect(),
-            alt_names: alt_names.into_iter().collect(),
-            ssl_context,
-        })
-    } else {
-        log::error!("Failed to create SSL context from cert paths");
-        None
+    // tls 1.3 still sends stateful tickets with NO_TICKET alone
+    if !tls_listen.session_tickets.unwrap_or(true) {
+        tls_settings.set_options(SslOptions::NO_TICKET);
+        tls_settings.set_num_tickets(0)?;
     }
-}
 
-fn create_ssl_context(
-    cert_path: &str,
-    key_path: &str,
-) -> Result<SslContext, Box<dyn std::error::Error>> {
-    let mut ctx = SslContext::builder(SslMethod::tls())?;
+    Ok((tls_settings, certificates))
+}
 
-    ctx.set_certificate_chain_file(cert_path)?;
-    ctx.set_private_key_file(key_path, SslFiletype::PEM)?;
-    ctx.set_alpn_select_callback(prefer_h2);
-    let built = ctx.build();
-    Ok(built)
+// the protocols in the length prefixed alpn wire format, h2 first by default
+fn alpn_wire(protocols: Option<&[String]>) -> Vec<u8> {
+    let Some(protocols) = protocols else {
+        return b"\x02h2\x08http/1.1".to_vec();
+    };
+    let mut wire = Vec::new();
+    for protocol in protocols {
+        wire.push(protocol.len() as u8);
+        wire.extend_from_slice(protocol.as_bytes());
+    }
+    wire
 }
 
-pub(crate) fn prefer_h2<'a>(_ssl: &mut SslRef, alpn_in: &'a [u8]) -> Result<&'a [u8], AlpnError> {
-    match select_next_proto("\x02h2\x08http/1.1".as_bytes(), alpn_in) {
+// the first of our protocols the client offers, the slice has to come
+// from the client list to outlive the callback
+fn select_alpn<'a>(protocols: &[u8], alpn_in: &'a [u8]) -> Result<&'a [u8], AlpnError> {
+    let selected = select_next_proto(protocols, alpn_in)
+        .and_then(|p| alpn_protocols(alpn_in).find(|e| *e == p));
+    match selected {
         Some(p) => Ok(p),
         _ => Err(AlpnError::NOACK), // unknown ALPN, just ignore it. Most clients will fallback to h1
     }
 }
+
+fn alpn_protocols(mut wire: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        let (len, rest) = wire.split_first()?;
+        let protocol = rest.get(..*len as usize)?;
+        wire = &rest[*len as usize..];
+        Some(protocol)
+    })
+}